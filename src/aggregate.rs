@@ -2,7 +2,13 @@ use image::{
     Rgb, Rgba,
 };
 
+/// Identifier stored in compressed files when no aggregation is used.
+pub const NO_AGGREGATOR: u8 = 0;
+
 pub trait Aggregator {
+    /// Identifier stored in compressed files, never `NO_AGGREGATOR`.
+    fn id(&self) -> u8;
+
     fn i32_from_rgb(&self, p: Rgb<u8>) -> i32;
     fn i32_from_rgba(&self, p: Rgba<u8>) -> i32;
 
@@ -12,7 +18,27 @@ pub trait Aggregator {
 pub struct Aggregator1;
 pub struct Aggregator2;
 
+/// Returns the aggregator whose `id()` is `id`, or `None` if there is no such
+/// aggregator (`NO_AGGREGATOR` included).
+pub fn from_id(id: u8) -> Option<Box<dyn Aggregator>> {
+    match id {
+        1 => Some(Box::new(Aggregator1)),
+        2 => Some(Box::new(Aggregator2)),
+        _ => None
+    }
+}
+
+/// Returns the id to store for `aggregator`.
+pub fn id_of(aggregator: &Option<Box<dyn Aggregator>>) -> u8 {
+    match aggregator {
+        Some(ag) => ag.id(),
+        None => NO_AGGREGATOR
+    }
+}
+
 impl Aggregator for Aggregator1 {
+    fn id(&self) -> u8 {  1  }
+
     fn i32_from_rgba(&self, p: Rgba<u8>) -> i32 {
         let mut r = 0_i32;
         for i in 0..8 {
@@ -69,17 +95,19 @@ impl Aggregator for Aggregator1 {
 }
 
 impl Aggregator for Aggregator2 {
+    fn id(&self) -> u8 {  2  }
+
     fn i32_from_rgb(&self, p: Rgb<u8>) -> i32 {
-        return (p[0] as i32) << 16_i32 +
-               (p[1] as i32) << 8_i32  + 
-                p[0] as i32
+        return ((p[0] as i32) << 16_i32) |
+               ((p[1] as i32) << 8_i32)  |
+                (p[2] as i32)
     }
 
     fn i32_from_rgba(&self, p: Rgba<u8>) -> i32 {
-        return (p[3] as i32) << 24_i32 +
-               (p[2] as i32) << 16_i32 +
-               (p[1] as i32) << 8_i32  + 
-                p[0] as i32
+        return ((p[0] as i32) << 24_i32) |
+               ((p[1] as i32) << 16_i32) |
+               ((p[2] as i32) << 8_i32)  |
+                (p[3] as i32)
     }
 
     fn rgb_from_i32(&self, x: i32) -> Rgb<u8> {
@@ -105,6 +133,9 @@ mod tests {
     
     use super::Aggregator;
     use super::Aggregator1;
+    use super::Aggregator2;
+    use super::from_id;
+    use image::{Rgb, Rgba};
 
     fn test_jspity(n: i32, aggregator: Box<dyn Aggregator>) {
         let p = aggregator.rgba_from_i32(n);
@@ -123,4 +154,25 @@ mod tests {
             i += 256;
         }
     }
+
+    #[test]
+    fn test_round_trip_pixels() {
+        for id in 1..3 {
+            let aggregator = from_id(id).unwrap();
+            assert_eq!(aggregator.id(), id);
+            for &c in [0_u8, 1, 17, 128, 200, 255].iter() {
+                let p = Rgba([c, c / 2, 255 - c, c ^ 0x5a]);
+                assert_eq!(aggregator.rgba_from_i32(aggregator.i32_from_rgba(p)), p);
+                let p = Rgb([c, 255 - c, c / 3]);
+                assert_eq!(aggregator.rgb_from_i32(aggregator.i32_from_rgb(p)), p);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_id() {
+        assert!(from_id(0).is_none());
+        assert!(from_id(3).is_none());
+        assert_eq!(Aggregator2.id(), 2);
+    }
 }
\ No newline at end of file
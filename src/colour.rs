@@ -0,0 +1,152 @@
+use nalgebra::DMatrix;
+
+/// Identifier stored in compressed files for planes holding r, g, b.
+pub const RGB: u8 = 0;
+/// Identifier stored in compressed files for planes holding Y, Cb, Cr.
+pub const YCBCR: u8 = 1;
+/// Identifier stored in compressed files for planes holding the mid and side
+/// of the left and right channels of a stereo sound.
+pub const MID_SIDE: u8 = 2;
+
+/// Weight of the error in the chroma planes when choosing the triplets to
+/// keep: the eye is much less sensitive to it than to the error in luma.
+pub const CHROMA_WEIGHT: f64 = 0.25;
+
+/// Space of the values stored in the planes of a planar file: colours of an
+/// image or channels of a sound (`Rgb` then meaning the channels as they 
+/// are).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColourSpace {
+    Rgb,
+    /// Full range YCbCr of JPEG (ITU-R BT.601), chroma centered on 128.
+    YCbCr,
+    /// `floor((l + r) / 2)` and `l - r` of a stereo sound, computed so that
+    /// left and right are recovered exactly.
+    MidSide,
+}
+
+impl ColourSpace {
+    /// Returns the colour space whose `id()` is `id`, if any.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            RGB => Some(ColourSpace::Rgb),
+            YCBCR => Some(ColourSpace::YCbCr),
+            MID_SIDE => Some(ColourSpace::MidSide),
+            _ => None
+        }
+    }
+
+    /// Identifier stored in compressed files.
+    pub fn id(&self) -> u8 {
+        match self {
+            ColourSpace::Rgb => RGB,
+            ColourSpace::YCbCr => YCBCR,
+            ColourSpace::MidSide => MID_SIDE,
+        }
+    }
+
+    /// Weight of the squared error of the plane `plane` (the alpha one
+    /// included) relative to the others.
+    pub fn weight(&self, plane: usize) -> f64 {
+        match (self, plane) {
+            (ColourSpace::YCbCr, 1) | (ColourSpace::YCbCr, 2) => CHROMA_WEIGHT,
+            _ => 1.0
+        }
+    }
+
+    /// Converts the first three `planes` from r, g, b to this colour space
+    /// (the first two from left, right for `MidSide`).
+    pub fn from_rgb(&self, planes: &mut [DMatrix<i32>]) {
+        match self {
+            ColourSpace::Rgb => {},
+            ColourSpace::YCbCr => convert(planes, |r, g, b| (
+                 0.299    * r + 0.587    * g + 0.114    * b,
+                -0.168736 * r - 0.331264 * g + 0.5      * b + 128.0,
+                 0.5      * r - 0.418688 * g - 0.081312 * b + 128.0,
+            )),
+            ColourSpace::MidSide => convert_pair(planes, |l, r| {
+                let side = l - r;
+                (r + (side >> 1), side)
+            }),
+        }
+    }
+
+    /// Converts the first three `planes` from this colour space to r, g, b
+    /// (the first two to left, right for `MidSide`).
+    pub fn to_rgb(&self, planes: &mut [DMatrix<i32>]) {
+        match self {
+            ColourSpace::Rgb => {},
+            ColourSpace::YCbCr => convert(planes, |y, cb, cr| (
+                y                          + 1.402    * (cr - 128.0),
+                y - 0.344136 * (cb - 128.0) - 0.714136 * (cr - 128.0),
+                y + 1.772    * (cb - 128.0),
+            )),
+            ColourSpace::MidSide => convert_pair(planes, |mid, side| {
+                let r = mid.wrapping_sub(side >> 1);
+                (r.wrapping_add(side), r)
+            }),
+        }
+    }
+}
+
+fn convert<F>(planes: &mut [DMatrix<i32>], f: F)
+    where F: Fn(f64, f64, f64) -> (f64, f64, f64)
+    {
+    let (h, w) = planes[0].shape();
+    for i in 0..h {
+        for j in 0..w {
+            let (a, b, c) = f(planes[0][(i, j)] as f64,
+                              planes[1][(i, j)] as f64,
+                              planes[2][(i, j)] as f64);
+            planes[0][(i, j)] = a.round() as i32;
+            planes[1][(i, j)] = b.round() as i32;
+            planes[2][(i, j)] = c.round() as i32;
+        }
+    }
+}
+
+fn convert_pair<F>(planes: &mut [DMatrix<i32>], f: F)
+    where F: Fn(i32, i32) -> (i32, i32)
+    {
+    let (h, w) = planes[0].shape();
+    for i in 0..h {
+        for j in 0..w {
+            let (a, b) = f(planes[0][(i, j)], planes[1][(i, j)]);
+            planes[0][(i, j)] = a;
+            planes[1][(i, j)] = b;
+        }
+    }
+}
+
+#[allow(dead_code, unused_imports)]
+mod tests {
+    use super::ColourSpace;
+    use nalgebra::DMatrix;
+
+    #[test]
+    fn test_round_trip() {
+        let mut planes: Vec<DMatrix<i32>> = (0..3).map(|c| {
+            DMatrix::from_fn(16, 16, |i, j| ((i * 16 + j) * (c + 3) % 256) as i32)
+        }).collect();
+        let original = planes.clone();
+
+        ColourSpace::YCbCr.from_rgb(&mut planes);
+        assert!(planes != original);
+        ColourSpace::YCbCr.to_rgb(&mut planes);
+        for c in 0..3 {
+            for (x, y) in planes[c].iter().zip(original[c].iter()) {
+                assert!((x - y).abs() <= 1);
+            }
+        }
+
+        let mut channels = vec![
+            DMatrix::from_fn(8, 8, |i, j| (i as i32 * 4001 - j as i32 * 977) % 32768),
+            DMatrix::from_fn(8, 8, |i, j| (j as i32 * 3001 - i as i32 * 1013) % 32768),
+        ];
+        let original = channels.clone();
+        ColourSpace::MidSide.from_rgb(&mut channels);
+        assert_eq!(channels[1], &original[0] - &original[1]);
+        ColourSpace::MidSide.to_rgb(&mut channels);
+        assert_eq!(channels, original);
+    }
+}
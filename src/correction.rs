@@ -0,0 +1,240 @@
+//! Correction stored after the body of near-lossless files, so that no
+//! decoded sample (channel of a pixel or audio sample) differs from the input
+//! one by more than a given tolerance `t`.
+//!
+//! The error `e` of each decoded sample is quantized with a step of `2t + 1`:
+//! `q = sign(e) * ((|e| + t) / (2t + 1))`, which is zero wherever the error is
+//! already within the tolerance, and decoding adds `q * (2t + 1)`. Only the
+//! samples whose `q` is not zero are stored, each one as the number of
+//! samples left as they are since the previous one and its `q`, coded like
+//! the residual of lossless files (see `residual`): a plane of zeros would
+//! cost at least 1/6 bit per sample once entropy coded, about 17 KB for a
+//! 512 x 512 RGB image whose approximation already is within the tolerance.
+//! Files of versions 9 and 10 store every `q`, like a residual.
+
+use super::{
+    Error,
+    read::FileReader,
+    write::FileWriter,
+    metrics::Samples,
+    residual::{read_residual, write_coded, read_coded, push_value, 
+               push_unsigned, next_value, next_unsigned, MAX_VALUE_SIZE},
+};
+
+use image::{ImageBuffer, Pixel};
+
+use nalgebra::DMatrix;
+
+use wav::BitDepth as WavData;
+
+pub struct Correction {
+    /// Quantization step, `2t + 1`.
+    step: i64,
+    /// Quantized error of each sample, shaped like the channels of `Samples`.
+    planes: Vec<DMatrix<i32>>,
+}
+
+impl Correction {
+    /// Correction of the samples `decoded` bringing them within `tolerance`
+    /// of the `original` ones.
+    pub fn between(original: &Samples, decoded: &Samples, tolerance: u32)
+        -> Self {
+        let step = 2 * tolerance as i64 + 1;
+        let planes = original.channels.iter().zip(decoded.channels.iter())
+            .map(|(a, b)| a.zip_map(b, |x, y| {
+                let e = x as i64 - y as i64;
+                (e.signum() * ((e.abs() + tolerance as i64) / step)) as i32
+            }))
+            .collect();
+        Correction { step, planes }
+    }
+
+    /// Number of samples that have to be corrected.
+    pub fn n_corrected(&self) -> usize {
+        self.planes.iter().map(|m| m.iter().filter(|&&q| q != 0).count())
+                          .sum()
+    }
+
+    /// Writes the corrected samples, taking the channels one after the other,
+    /// row after row.
+    pub fn write(&self, fw: &mut FileWriter) -> Result<(), Error> {
+        let mut bytes = Vec::new();
+        let mut skipped = 0;
+        for m in self.planes.iter() {
+            for i in 0..m.nrows() {
+                for j in 0..m.ncols() {
+                    if m[(i, j)] == 0 {
+                        skipped += 1;
+                        continue;
+                    }
+                    push_unsigned(&mut bytes, skipped);
+                    push_value(&mut bytes, m[(i, j)]);
+                    skipped = 0;
+                }
+            }
+        }
+        write_coded(fw, &bytes)
+    }
+
+    /// Reads the correction of `n_planes` channels of `height x width`
+    /// samples (the width and height of an image, one row of samples for a
+    /// sound), `sparse` telling whether only the corrected samples are stored
+    /// (see `FileHeader::sparse_correction`).
+    pub fn read(fr: &mut FileReader, tolerance: u32, sparse: bool, 
+                n_planes: usize, height: usize, width: usize) 
+        -> Result<Self, Error> {
+        let step = 2 * tolerance as i64 + 1;
+        if !sparse {
+            let planes = read_residual(fr, n_planes, height, width)?;
+            return Ok(Correction { step, planes });
+        }
+
+        let size = height * width;
+        let bytes = read_coded(fr, 2 * MAX_VALUE_SIZE * n_planes * size)?;
+        let mut planes = vec![DMatrix::<i32>::zeros(height, width); n_planes];
+        let (mut pos, mut k) = (0, 0);
+        while pos < bytes.len() {
+            k += next_unsigned(&bytes, &mut pos)? as usize;
+            let q = next_value(&bytes, &mut pos)?;
+            if k >= n_planes * size {
+                return Err(Error::InvalidHeader("invalid corrected sample"));
+            }
+            planes[k / size][(k % size / width, k % width)] = q;
+            k += 1;
+        }
+        Ok(Correction { step, planes })
+    }
+
+    /// Corrects `x`, keeping the result in `[lo, hi]` (the original sample
+    /// being in there too, this does not move it further).
+    fn corrected(&self, x: i64, q: i32, lo: i64, hi: i64) -> i64 {
+        (x + q as i64 * self.step).max(lo).min(hi)
+    }
+
+    /// Corrects the samples computed by the encoder, that lie in `[lo, hi]`.
+    pub fn apply_to_samples(&self, samples: &mut Samples, lo: i64, hi: i64) {
+        for (c, q) in samples.channels.iter_mut().zip(self.planes.iter()) {
+            c.zip_apply(q, |x, q| self.corrected(x as i64, q, lo, hi) as f64);
+        }
+    }
+
+    pub fn apply_to_image<P>(&self, img: &mut ImageBuffer<P, Vec<u8>>)
+        where P: Pixel<Subpixel = u8> + 'static
+        {
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            for (c, q) in pixel.channels_mut().iter_mut().zip(self.planes.iter()) {
+                let q = q[(x as usize, y as usize)];
+                *c = self.corrected(*c as i64, q, 0, 0xff) as u8;
+            }
+        }
+    }
+
+    /// Corrects the samples of a sound, 24-bit ones at their value (`wav`
+    /// keeps them in the 3 upper bytes of an `i32`).
+    pub fn apply_to_sound(&self, data: &mut WavData) {
+        let q = &self.planes[0];
+        match data {
+            WavData::Eight(v) => for (k, x) in v.iter_mut().enumerate() {
+                *x = self.corrected(*x as i64, q[k], 0, 0xff) as u8;
+            },
+            WavData::Sixteen(v) => for (k, x) in v.iter_mut().enumerate() {
+                *x = self.corrected(*x as i64, q[k], i16::MIN as i64,
+                                    i16::MAX as i64) as i16;
+            },
+            WavData::TwentyFour(v) => for (k, x) in v.iter_mut().enumerate() {
+                *x = (self.corrected((*x >> 8) as i64, q[k], -(1 << 23),
+                                     (1 << 23) - 1) as i32) << 8;
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Range of the samples of a sound of `bits_per_sample` bits (or of image
+/// channels if `None`).
+pub fn sample_range(bits_per_sample: Option<u16>) -> (i64, i64) {
+    match bits_per_sample {
+        Some(8) | None => (0, 0xff),
+        Some(b) => (-(1 << (b - 1)), (1 << (b - 1)) - 1),
+    }
+}
+
+#[allow(dead_code, unused_imports)]
+mod tests {
+    use super::Correction;
+    use crate::{
+        metrics::{Samples, max_error},
+        read::FileReader,
+        write::FileWriter,
+        residual::write_residual,
+    };
+    use nalgebra::DMatrix;
+
+    #[test]
+    fn test_bounded_error() {
+        let original = Samples {
+            channels: vec![DMatrix::from_fn(6, 5, |i, j| ((i * 40 + j * 17) % 256) as f64)],
+            peak: 255.0,
+        };
+        let mut decoded = Samples {
+            channels: vec![original.channels[0].map(|x| (x / 32.0).round() * 32.0)],
+            peak: 255.0,
+        };
+        for &t in [0, 1, 3, 10, 40].iter() {
+            let correction = Correction::between(&original, &decoded, t);
+            let mut corrected = Samples { channels: decoded.channels.clone(),
+                                          peak: 255.0 };
+            correction.apply_to_samples(&mut corrected, 0, 255);
+            assert!(max_error(&original.channels, &corrected.channels) <= t as f64);
+        }
+        decoded.channels[0].fill(0.0);
+        assert_eq!(Correction::between(&original, &decoded, 300).n_corrected(), 0);
+    }
+
+    #[test]
+    fn test_storage() {
+        let planes = vec![
+            DMatrix::from_fn(6, 5, |i, j| match (i, j) {
+                (0, 0) => -3,
+                (2, 4) => 1,
+                _ => 0,
+            }),
+            DMatrix::zeros(6, 5),
+            DMatrix::from_fn(6, 5, |i, j| if (i, j) == (5, 4) { 200 } else { 0 }),
+        ];
+        let correction = Correction { step: 3, planes: planes.clone() };
+        assert_eq!(correction.n_corrected(), 3);
+        let mut fw = FileWriter::in_memory();
+        correction.write(&mut fw).unwrap();
+        fw.write_u8(0xab).unwrap();
+        let mut fr = FileReader::from_bytes(fw.into_bytes());
+        let read = Correction::read(&mut fr, 1, true, 3, 6, 5).unwrap();
+        assert_eq!(read.planes, planes);
+        assert_eq!(read.step, 3);
+        assert_eq!(fr.read_u8().unwrap(), 0xab);
+
+        // files of previous versions store every sample
+        let mut fw = FileWriter::in_memory();
+        write_residual(&mut fw, &planes).unwrap();
+        let mut fr = FileReader::from_bytes(fw.into_bytes());
+        assert_eq!(Correction::read(&mut fr, 1, false, 3, 6, 5).unwrap().planes,
+                   planes);
+
+        // a few corrected samples of a large image cost a few bytes
+        let mut planes = vec![DMatrix::zeros(512, 512); 3];
+        planes[1][(100, 200)] = 2;
+        let correction = Correction { step: 3, planes: planes.clone() };
+        let mut sparse = FileWriter::in_memory();
+        correction.write(&mut sparse).unwrap();
+        let mut dense = FileWriter::in_memory();
+        write_residual(&mut dense, &planes).unwrap();
+        assert!(sparse.into_bytes().len() < 32);
+        assert!(dense.into_bytes().len() > 10_000);
+
+        // a corrected sample after the last one is rejected
+        let mut fw = FileWriter::in_memory();
+        Correction { step: 3, planes }.write(&mut fw).unwrap();
+        let mut fr = FileReader::from_bytes(fw.into_bytes());
+        assert!(Correction::read(&mut fr, 1, true, 1, 512, 512).is_err());
+    }
+}
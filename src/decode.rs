@@ -4,22 +4,39 @@ use super::{
     write::FileWriter,
     encode::{
         Options,
+        Sample,
         SVDVectors,
+        WriteVectors,
+        energy_of,
+        singular_values,
+        plot_if_asked,
+        report_energy,
         write_vectors_header,
+        write_payload,
         write_vectors_f32,
         write_vectors_f64,
     },
-    aggregate::Aggregator,
+    aggregate::{self, Aggregator},
+    colour::ColourSpace,
+    header::FileHeader,
+    entropy,
+    residual::{read_residual, add_residual},
+    correction::{Correction, sample_range},
+    layout::Layout,
+    segment::decode_segments,
+    tile::{Tiling, read_tiles, write_tiles, recompute_tiles, 
+           remove_tile_vectors},
 };
 
 use std::{
     path::Path,
-    fs::File
+    fs::File,
+    marker::PhantomData,
 };
 
-use nalgebra::{ DMatrix, DVector, Scalar };
+use nalgebra::{ DMatrix, DVector, RealField, Scalar };
 
-use image::{ImageBuffer, Rgb, Rgba, RgbImage, RgbaImage};
+use image::{ImageBuffer, Pixel, Rgb, Rgba, RgbImage, RgbaImage};
 
 use wav::{
     BitDepth as WavData,
@@ -28,36 +45,73 @@ use wav::{
 
 pub fn decode(input: &str, output: &str, options: &mut Options) -> Result<(), Error> {
 
-    let (is_sound, use_f64, with_alpha, aggregate, header, mut fr) =
-        read_file_header(input)?;
+    let (file_header, mut fr) = read_file_header(input)?;
 
-    let matrix = if use_f64 {
-        let vectors: SVDVectors<f64> = read_file_f64(&mut fr)?;
-        // println!("{}", vectors.to_string());
-        let m = recompute_matrix_f64(&vectors)?;
-        println!("found & recomputed: {}", m);
-        m
+    if file_header.segment_length.is_some() {
+        return decode_segments(&mut fr, &file_header, output, options);
     }
-    else {
-        let vectors = read_file_f32(&mut fr)?;
-        // println!("{}", vectors.to_string());
-        recompute_matrix_f32(&vectors)?
-    };
+    if let Some(h) = file_header.wav.filter(|_| file_header.float_samples()) {
+        let planes: Vec<DMatrix<f32>> = 
+            read_approximation(&mut fr, &file_header, options)?;
+        let raw_data = sound_from_float_planes(&planes, h, file_header.layout());
+        let mut out_file = File::create(Path::new(output))?;
+        wav::write(h.0, raw_data, &mut out_file)?;
+        return Ok(());
+    }
+
+    let mut planes: Vec<DMatrix<i32>> = 
+        read_approximation(&mut fr, &file_header, options)?;
 
-    if !aggregate {  options.aggregator = None;  }
+    options.aggregator = aggregate::from_id(file_header.aggregator);
 
-    if is_sound {
-        let h = header.unwrap();
-        let raw_data = sound_from_matrix(&matrix, h);
+    if file_header.planar {
+        if let Some(space) = ColourSpace::from_id(file_header.colour_space) {
+            space.to_rgb(&mut planes);
+        }
+    }
+    // the residual only completes the whole approximation
+    if file_header.lossless && options.rank.is_none() {
+        let (h, w) = planes[0].shape();
+        let residual = read_residual(&mut fr, planes.len(), h, w)?;
+        add_residual(&mut planes, &residual);
+    }
+
+    // so does the correction
+    let tolerance = file_header.tolerance.filter(|_| options.rank.is_none());
+    let sparse = file_header.sparse_correction();
+
+    if let Some(h) = file_header.wav {
+        if file_header.shifted_samples() {
+            for p in planes.iter_mut() {
+                p.apply(|x| x >> 8);
+            }
+        }
+        let mut raw_data = sound_from_planes(&planes, h, file_header.layout());
+        if let Some(t) = tolerance {
+            Correction::read(&mut fr, t, sparse, 1, 1, h.1 as usize)?
+                .apply_to_sound(&mut raw_data);
+        }
         let mut out_file = File::create(Path::new(output))?;
         wav::write(h.0, raw_data, &mut out_file)?;
 
+    } else if file_header.planar {
+        if file_header.with_alpha {
+            let mut imgbuf = imgbuf_from_planes_rgba(&planes);
+            correct_image(&mut imgbuf, &mut fr, tolerance, sparse)?;
+            imgbuf.save(output).unwrap();
+        } else {
+            let mut imgbuf = imgbuf_from_planes_rgb(&planes);
+            correct_image(&mut imgbuf, &mut fr, tolerance, sparse)?;
+            imgbuf.save(output).unwrap();
+        }
     } else {
-        if with_alpha {
-            let imgbuf = imgbuf_from_matrix_rgba(&matrix, &options.aggregator)?;
+        if file_header.with_alpha {
+            let mut imgbuf = imgbuf_from_matrix_rgba(&planes[0], &options.aggregator)?;
+            correct_image(&mut imgbuf, &mut fr, tolerance, sparse)?;
             imgbuf.save(output).unwrap();
         } else {
-            let imgbuf = imgbuf_from_matrix_rgb(&matrix, &options.aggregator)?;
+            let mut imgbuf = imgbuf_from_matrix_rgb(&planes[0], &options.aggregator)?;
+            correct_image(&mut imgbuf, &mut fr, tolerance, sparse)?;
             imgbuf.save(output).unwrap();
         }
     }
@@ -65,59 +119,308 @@ pub fn decode(input: &str, output: &str, options: &mut Options) -> Result<(), Er
     Ok(())
 }
 
-pub fn reduce(input: &str, output: &str, options: &Options) 
+/// Reads the correction of the decoded `img` from `fr` and applies it, if
+/// there is a `tolerance`, see `Correction::read`.
+fn correct_image<P>(img: &mut ImageBuffer<P, Vec<u8>>, fr: &mut FileReader,
+                    tolerance: Option<u32>, sparse: bool) -> Result<(), Error>
+    where P: Pixel<Subpixel = u8> + 'static
+    {
+    if let Some(t) = tolerance {
+        let (w, h) = img.dimensions();
+        Correction::read(fr, t, sparse, P::CHANNEL_COUNT as usize, w as usize, 
+                         h as usize)?.apply_to_image(img);
+    }
+    Ok(())
+}
+
+/// Recomputes the planes of the file from its triplets (the first
+/// `options.rank` ones of each tile, if given), `fr` being right after the
+/// header.
+pub(crate) fn read_approximation<T: Sample>(fr: &mut FileReader, file_header: &FileHeader,
+                                 options: &Options)
+    -> Result<Vec<DMatrix<T>>, Error> {
+
+    if file_header.tiled {
+        if file_header.use_f64 {
+            let (tiling, mut tiles) = read_tiles(fr, file_header, 
+                                                 read_vectors_f64)?;
+            keep_rank(&mut tiles, options.rank);
+            recompute_tiles(&tiling, &tiles, recompute_matrix_f64)
+        } else {
+            let (tiling, mut tiles) = read_tiles(fr, file_header, 
+                                                 read_vectors_f32)?;
+            keep_rank(&mut tiles, options.rank);
+            recompute_tiles(&tiling, &tiles, recompute_matrix_f32)
+        }
+    }
+    else if let Some(k) = options.rank {
+        // the rest of the file is not needed
+        let fr = std::mem::replace(fr, FileReader::from_bytes(Vec::new()));
+        let mut m = None;
+        for refined in Refinements::new(fr, file_header)?.take(k) {
+            m = Some(refined?);
+        }
+        m.ok_or(Error::NTooSmall)
+    }
+    else if file_header.use_f64 {
+        let vectors: SVDVectors<f64> = read_file_f64(fr, file_header)?;
+        // println!("{}", vectors.to_string());
+        Ok(vec![recompute_matrix_f64(&vectors)?])
+    }
+    else {
+        let vectors = read_file_f32(fr, file_header)?;
+        // println!("{}", vectors.to_string());
+        Ok(vec![recompute_matrix_f32(&vectors)?])
+    }
+}
+
+/// Keeps in `output` the triplets of the compressed `input` asked by the
+/// compression policy of `options`. The original being unknown, a quality
+/// or an energy is measured against the stored approximation rather than
+/// the original: the error of the stored file adds to the one reported.
+pub fn reduce(input: &str, output: &str, options: &mut Options) 
     -> Result<(), Error> {
 
-    let (_is_sound, use_f64, _with_alpha, _aggregate, header, mut fr) =
-        read_file_header(input)?;
+    let (mut file_header, mut fr) = read_file_header(input)?;
+    if file_header.segment_length.is_some() {
+        return Err(Error::UnsupportedMode("reduction of a segmented sound"));
+    }
+    // the original is unknown: -p is a percentage of the compressed input
+    options.original_file_size = std::fs::metadata(input)?.len();
+    options_from_header(options, &file_header);
+    drop_residual(&mut file_header);
 
     let mut fw = FileWriter::from_name(output)?;
 
-    if use_f64 {
-        let mut vectors = read_file_f64(&mut fr)?;
-        remove_vectors(&mut vectors, options)?;
-
-        write_vectors_header(&mut fw, &vectors, options, header)?;
-        write_vectors_f64(&mut fw, &vectors)?;
+    if file_header.tiled {
+        file_header.write(&mut fw)?;
+        if file_header.use_f64 {
+            let (tiling, mut tiles) = read_tiles(&mut fr, &file_header, 
+                                                 read_vectors_f64)?;
+            tiles.iter_mut().for_each(|t| unshift_values(t, &file_header));
+            remove_tile_vectors(&mut tiles, &tiling, options, 
+                                write_vectors_f64)?;
+            write_tiles(&mut fw, &file_header, &tiling, &tiles, 
+                        write_vectors_f64)?;
+        } else {
+            let (tiling, mut tiles) = read_tiles(&mut fr, &file_header, 
+                                                 read_vectors_f32)?;
+            tiles.iter_mut().for_each(|t| unshift_values(t, &file_header));
+            remove_tile_vectors(&mut tiles, &tiling, options, 
+                                write_vectors_f32)?;
+            write_tiles(&mut fw, &file_header, &tiling, &tiles, 
+                        write_vectors_f32)?;
+        }
+    }
+    else if file_header.use_f64 {
+        let mut vectors = read_file_f64(&mut fr, &file_header)?;
+        unshift_values(&mut vectors, &file_header);
+        remove_vectors(&mut vectors, options, write_vectors_f64)?;
+
+        write_vectors_header(&mut fw, &vectors, &file_header)?;
+        write_payload(&mut fw, &file_header, 
+                      |w| write_vectors_f64(w, &vectors, &file_header))?;
     }
     else {
-        let mut vectors = read_file_f32(&mut fr)?;
-        remove_vectors(&mut vectors, options)?;
+        let mut vectors = read_file_f32(&mut fr, &file_header)?;
+        unshift_values(&mut vectors, &file_header);
+        remove_vectors(&mut vectors, options, write_vectors_f32)?;
 
-        write_vectors_header(&mut fw, &vectors, options, header)?;
-        write_vectors_f32(&mut fw, &vectors)?;
+        write_vectors_header(&mut fw, &vectors, &file_header)?;
+        write_payload(&mut fw, &file_header, 
+                      |w| write_vectors_f32(w, &vectors, &file_header))?;
     }
     
     Ok(())
 }
 
-fn read_file_header(input: &str) 
-    -> Result<(bool, bool, bool, bool, Option<(WavHeader, u32)>, FileReader), 
-               Error>{
+pub(crate) fn read_file_header(input: &str) -> Result<(FileHeader, FileReader), Error> {
 
     let f = File::open(input)?;
     let mut fr = FileReader::new(f);
-    let content_type = fr.read_u8()?;
-    let is_sound =   if content_type & 0x8 != 0 { true } else { false };
-    let use_f64 =    if content_type & 0x4 != 0 { true } else { false };
-    let with_alpha = if content_type & 0x2 != 0 { true } else { false };
-    let aggregate =  if content_type & 0x1 != 0 { true } else { false };
-
-    let header: Option<(WavHeader, u32)> = if is_sound {
-        let mut header_raw = [0_u8; 16];
-        for i in 0..16 {
-            header_raw[i] = fr.read_u8()?;
+    let header = FileHeader::read(&mut fr)?;
+
+    Ok((header, fr))
+}
+
+/// Reads the body of `size` bytes with `read`, decompressing it first if the
+/// header says so.
+pub(crate) fn read_payload<T, F>(fr: &mut FileReader, header: &FileHeader, size: usize, 
+                      read: F) -> Result<T, Error>
+    where F: FnOnce(&mut FileReader) -> Result<T, Error>
+    {
+    if header.entropy_coded {
+        let len = fr.read_u32()? as usize;
+        if len as u64 > fr.remaining()? {
+            return Err(Error::InvalidHeader("file is truncated"));
         }
-        let n = fr.read_u32()?;
-        Some((header_raw.into(), n))
-    } else { None };
+        let compressed = fr.read_bytes(len)?;
+        let bytes = entropy::decompress(&compressed, size, 
+                                        header.symbol_period())?;
+        read(&mut FileReader::from_bytes(bytes))
+    } else {
+        read(fr)
+    }
+}
 
-    Ok((is_sound, use_f64, with_alpha, aggregate, header, fr))
+/// Sets the storage `options` to the ones of a file with the given `header`.
+pub(crate) fn options_from_header(options: &mut Options, header: &FileHeader) {
+    options.use_f64 = header.use_f64;
+    options.entropy_coding = header.entropy_coded;
+    options.with_alpha = header.with_alpha;
+    options.aggregator = aggregate::from_id(header.aggregator);
+    options.planar = header.planar;
+    options.is_wav = header.is_sound;
+    options.bits_per_sample = header.wav.map(|h| h.0.bits_per_sample);
+    options.colour_space = ColourSpace::from_id(header.colour_space)
+        .unwrap_or(ColourSpace::Rgb);
+    options.layout = header.layout();
+    options.quantization = match header.quantization {
+        0 => None,
+        b => Some(b)
+    };
 }
 
-fn read_file_f64(fr: &mut FileReader) -> Result<SVDVectors<f64>, Error> {
+/// Clears the lossless flag and the tolerance of a file whose triplets are
+/// about to change, as its residual or correction does not apply to them
+/// anymore.
+pub(crate) fn drop_residual(header: &mut FileHeader) {
+    if header.lossless {
+        println!("The residual is dropped, the result is not lossless.");
+        header.lossless = false;
+    }
+    if let Some(t) = header.tolerance.take() {
+        println!("The correction is dropped, the error may exceed {}.", t);
+    }
+}
 
-    let (n, height, width) = read_file_dimensions(fr)?;
+/// Keeps at most `rank` triplets in every tile.
+fn keep_rank<N: Scalar>(tiles: &mut Vec<SVDVectors<N>>, rank: Option<usize>) {
+    if let Some(k) = rank {
+        for vectors in tiles.iter_mut() {
+            vectors.truncate(k);
+        }
+    }
+}
+
+/// Successive approximations of the planes stored in a compressed file, the
+/// `k`-th one being recomputed from the first `k` triplets (of every tile,
+/// if the file is tiled). Without tiles, a triplet is only read when the
+/// next approximation is asked, so that a preview is available before the
+/// whole file is read (unless the body is entropy coded, as it is then
+/// decompressed at once). The tiles being stored one after the other, their
+/// triplets are all read at first.
+pub struct Refinements<'a, T = i32> {
+    fr: FileReader,
+    header: &'a FileHeader,
+    n: usize,
+    read: usize,
+    /// A single tile covering the matrix if the file is not tiled.
+    tiling: Tiling,
+    /// The triplets of every tile, if the file is tiled.
+    tiles: Option<Vec<SVDVectors<f64>>>,
+    blocks: Vec<DMatrix<f64>>,
+    sample: PhantomData<T>,
+}
+
+impl<'a, T: Sample> Refinements<'a, T> {
+    /// Reads the dimensions of the matrix from `fr`, that must be right 
+    /// after `header`.
+    pub fn new(mut fr: FileReader, header: &'a FileHeader) 
+        -> Result<Self, Error> {
+        let (n, tiling, tiles) = if header.tiled {
+            let (tiling, tiles) = if header.use_f64 {
+                read_tiles(&mut fr, header, read_vectors_f64)?
+            } else {
+                let (tiling, tiles) = read_tiles(&mut fr, header, 
+                                                 read_vectors_f32)?;
+                (tiling, tiles.iter().map(to_f64).collect())
+            };
+            let n = tiles.iter().map(|t| t.len()).max().unwrap_or(0);
+            (n, tiling, Some(tiles))
+        } else {
+            let (n, height, width) = read_file_dimensions(&mut fr, header)?;
+            if header.entropy_coded {
+                let size = n * header.triplet_size(height, width);
+                let bytes = read_payload(&mut fr, header, size, 
+                                         |r| Ok(r.read_bytes(size)?))?;
+                fr = FileReader::from_bytes(bytes);
+            }
+            (n, Tiling::new(height, width, height, width), None)
+        };
+        let blocks = tiling.blocks().iter()
+                           .map(|&(h, w)| DMatrix::zeros(h, w))
+                           .collect();
+        Ok(Refinements { fr, header, n, read: 0, tiling, tiles, blocks,
+                         sample: PhantomData })
+    }
+
+    fn read_triplet(&mut self) -> Result<(), Error> {
+        if let Some(tiles) = &self.tiles {
+            // some tiles may have less triplets than others
+            for (m, vectors) in self.blocks.iter_mut().zip(tiles) {
+                if let Some((sv, u, v)) = vectors.get(self.read) {
+                    m.ger(*sv, u, v, 1.0);
+                }
+            }
+        } else {
+            let (h, w) = (self.tiling.height, self.tiling.width);
+            let triplet = if self.header.use_f64 {
+                read_vectors_f64(&mut self.fr, self.header, 1, h, w)?
+            } else {
+                to_f64(&read_vectors_f32(&mut self.fr, self.header, 1, h, w)?)
+            };
+            let (sv, u, v) = &triplet[0];
+            self.blocks[0].ger(*sv, u, v, 1.0);
+        }
+        self.read += 1;
+        Ok(())
+    }
+}
+
+impl<'a, T: Sample> Iterator for Refinements<'a, T> {
+    type Item = Result<Vec<DMatrix<T>>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.read == self.n {
+            return None;
+        }
+        if let Err(e) = self.read_triplet() {
+            // nothing sensible can be read after an error
+            self.read = self.n;
+            return Some(Err(e));
+        }
+        let blocks: Vec<DMatrix<T>> = self.blocks.iter()
+                                          .map(|m| m.map(T::from_f64))
+                                          .collect();
+        Some(Ok(self.tiling.assemble_planes(&blocks)))
+    }
+}
+
+pub(crate) fn to_f64(vectors: &SVDVectors<f32>) -> SVDVectors<f64> {
+    vectors.iter().map(|(sv, u, v)| {
+        (*sv as f64,
+         DVector::from_iterator(u.len(), u.iter().map(|&x| x as f64)),
+         DVector::from_iterator(v.len(), v.iter().map(|&x| x as f64)))
+    }).collect()
+}
+
+fn read_file_f64(fr: &mut FileReader, header: &FileHeader)
+    -> Result<SVDVectors<f64>, Error> {
+
+    let (n, height, width) = read_file_dimensions(fr, header)?;
+    let size = n * header.triplet_size(height, width);
+    read_payload(fr, header, size, 
+                 |r| read_vectors_f64(r, header, n, height, width))
+}
+
+/// Reads `n` triplets of a `height x width` matrix, see `read_vectors_f64`.
+pub type ReadVectors<T> = fn(&mut FileReader, &FileHeader, usize, usize, usize)
+    -> Result<SVDVectors<T>, Error>;
+
+pub(crate) fn read_vectors_f64(fr: &mut FileReader, header: &FileHeader, 
+                    n: usize, height: usize, width: usize)
+    -> Result<SVDVectors<f64>, Error> {
 
     let mut res = Vec::with_capacity(n);
 
@@ -125,10 +428,19 @@ fn read_file_f64(fr: &mut FileReader) -> Result<SVDVectors<f64>, Error> {
         let sv_i = fr.read_f64()?;
         let mut u_i   = DVector::<f64>::zeros(height);
         let mut v_t_i = DVector::<f64>::zeros(width);
-        for j in 0..height 
-            { u_i[j] = fr.read_f64()?; }
-        for j in 0..width 
-            { v_t_i[j] = fr.read_f64()?; }
+        if header.quantization == 0 {
+            for j in 0..height 
+                { u_i[j] = fr.read_f64()?; }
+            for j in 0..width 
+                { v_t_i[j] = fr.read_f64()?; }
+        } else {
+            for v in [&mut u_i, &mut v_t_i].iter_mut() {
+                let scale = fr.read_f64()?;
+                let q = read_quantized(fr, v.nrows(), header.quantization)?;
+                for j in 0..q.len() 
+                    { v[j] = q[j] * scale; }
+            }
+        }
 
         res.push((sv_i, u_i, v_t_i));
     }
@@ -136,9 +448,18 @@ fn read_file_f64(fr: &mut FileReader) -> Result<SVDVectors<f64>, Error> {
     Ok(res)
 }
 
-fn read_file_f32(fr: &mut FileReader) -> Result<SVDVectors<f32>, Error> {
+fn read_file_f32(fr: &mut FileReader, header: &FileHeader)
+    -> Result<SVDVectors<f32>, Error> {
+
+    let (n, height, width) = read_file_dimensions(fr, header)?;
+    let size = n * header.triplet_size(height, width);
+    read_payload(fr, header, size, 
+                 |r| read_vectors_f32(r, header, n, height, width))
+}
 
-    let (n, height, width) = read_file_dimensions(fr)?;
+pub(crate) fn read_vectors_f32(fr: &mut FileReader, header: &FileHeader, 
+                    n: usize, height: usize, width: usize)
+    -> Result<SVDVectors<f32>, Error> {
 
     let mut res = Vec::with_capacity(n);
 
@@ -146,10 +467,19 @@ fn read_file_f32(fr: &mut FileReader) -> Result<SVDVectors<f32>, Error> {
         let sv_i = fr.read_f32()?;
         let mut u_i   = DVector::<f32>::zeros(height);
         let mut v_t_i = DVector::<f32>::zeros(width);
-        for j in 0..height 
-            { u_i[j] = fr.read_f32()?; }
-        for j in 0..width 
-            { v_t_i[j] = fr.read_f32()?; }
+        if header.quantization == 0 {
+            for j in 0..height 
+                { u_i[j] = fr.read_f32()?; }
+            for j in 0..width 
+                { v_t_i[j] = fr.read_f32()?; }
+        } else {
+            for v in [&mut u_i, &mut v_t_i].iter_mut() {
+                let scale = fr.read_f32()?;
+                let q = read_quantized(fr, v.nrows(), header.quantization)?;
+                for j in 0..q.len() 
+                    { v[j] = q[j] as f32 * scale; }
+            }
+        }
 
         res.push((sv_i, u_i, v_t_i));
     }
@@ -157,15 +487,37 @@ fn read_file_f32(fr: &mut FileReader) -> Result<SVDVectors<f32>, Error> {
     Ok(res)
 }
 
-fn read_file_dimensions(fr: &mut FileReader) -> Result<(usize, usize, usize), Error> {
+/// Reads `n` values written by `encode::write_quantized`, scaled back to 
+/// `[-1, 1]`.
+fn read_quantized(fr: &mut FileReader, n: usize, bits: u8)
+    -> Result<Vec<f64>, Error> {
+
+    let q_max = ((1_i32 << (bits - 1)) - 1) as f64;
+    let sign = 1_i32 << (bits - 1);
+
+    let mut res = Vec::with_capacity(n);
+    for _i in 0..n {
+        let x = fr.read_bits(bits)? as i32;
+        // sign extension
+        let q = if x & sign != 0 { x - (sign << 1) } else { x };
+        res.push(q as f64 / q_max);
+    }
+    fr.align_bits();
+
+    Ok(res)
+}
+
+pub(crate) fn read_file_dimensions(fr: &mut FileReader, header: &FileHeader)
+    -> Result<(usize, usize, usize), Error> {
     let n = fr.read_u32()? as usize;
     let height = fr.read_u32()? as usize;
     let width  = fr.read_u32()? as usize;
+    header.validate_dimensions(n, height, width, fr.remaining()?)?;
     Ok((n, height, width))
 }
 
-pub (crate) fn recompute_matrix_f64(vectors: &SVDVectors<f64>) 
-    -> Result<DMatrix<i32>, Error> {
+pub (crate) fn recompute_matrix_f64<T: Sample>(vectors: &SVDVectors<f64>) 
+    -> Result<DMatrix<T>, Error> {
     
     let n = vectors.len();
     if n <= 0  {  return Err(Error::NTooSmall);  }
@@ -185,15 +537,13 @@ pub (crate) fn recompute_matrix_f64(vectors: &SVDVectors<f64>)
         }
     }
 
-    let m2 = DMatrix::from_fn(height, width, |i, j| {
-        m[(i, j)].round() as i32
-    });
+    let m2 = DMatrix::from_fn(height, width, |i, j| T::from_f64(m[(i, j)]));
 
     Ok(m2)
 }
 
-pub (crate) fn recompute_matrix_f32(vectors: &SVDVectors<f32>) 
-    -> Result<DMatrix<i32>, Error> {
+pub (crate) fn recompute_matrix_f32<T: Sample>(vectors: &SVDVectors<f32>) 
+    -> Result<DMatrix<T>, Error> {
     
     let n = vectors.len();
     if n <= 0  {  return Err(Error::NTooSmall);  }
@@ -213,9 +563,7 @@ pub (crate) fn recompute_matrix_f32(vectors: &SVDVectors<f32>)
         }
     }
 
-    let m2 = DMatrix::from_fn(height, width, |i, j| {
-        m[(i, j)].round() as i32
-    });
+    let m2 = DMatrix::from_fn(height, width, |i, j| T::from_f32(m[(i, j)]));
 
     Ok(m2)
 }
@@ -286,51 +634,387 @@ pub (crate) fn imgbuf_from_matrix_rgb(matrix: &DMatrix<i32>,
     }
 }
 
-pub (crate) fn sound_from_matrix(matrix: &DMatrix<i32>, header: (WavHeader, u32)) 
+/// Image whose channels are the `planes` (r, g, b, a), values outside of
+/// `[0, 255]` being clamped.
+pub (crate) fn imgbuf_from_planes_rgba(planes: &[DMatrix<i32>]) -> RgbaImage {
+    let (width, height) = planes[0].shape();
+    ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
+        let c = |k: usize| clamp_u8(planes[k][(x as usize, y as usize)]);
+        Rgba([c(0), c(1), c(2), c(3)])
+    })
+}
+
+/// Same as `imgbuf_from_planes_rgba`, without alpha channel.
+pub (crate) fn imgbuf_from_planes_rgb(planes: &[DMatrix<i32>]) -> RgbImage {
+    let (width, height) = planes[0].shape();
+    ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
+        let c = |k: usize| clamp_u8(planes[k][(x as usize, y as usize)]);
+        Rgb([c(0), c(1), c(2)])
+    })
+}
+
+fn clamp_u8(x: i32) -> u8 {
+    x.max(0).min(0xff) as u8
+}
+
+/// Interleaves the samples of the channels of a sound, one per plane, laid
+/// out as given by `layout` (see `encode::matrices_from_sound_data`). A
+/// single plane holds every sample, whatever the number of channels.
+fn interleave<T: Sample>(planes: &[DMatrix<T>], n: u32, layout: Layout) 
+    -> Vec<f64> {
+    let channels = planes.len();
+    let frames = (n as usize + channels - 1) / channels;
+    let samples: Vec<Vec<f64>> = planes.iter().map(|p| {
+        layout.from_matrix(&p.map(|x| x.into()), frames)
+    }).collect();
+    (0_usize..n as usize).map(|k| samples[k % channels][k / channels])
+                         .collect()
+}
+
+/// Samples of a sound with integer samples, see `interleave`. They are
+/// rounded and clamped to the range of the bit depth (24-bit samples being
+/// then shifted to the 3 upper bytes of an `i32`, as `wav` expects).
+pub (crate) fn sound_from_planes(planes: &[DMatrix<i32>], 
+                                 header: (WavHeader, u32), layout: Layout)
     -> WavData {
     
-    let n = header.1;
-    let (rows, _cols) = matrix.shape();
-    let mut x = vec![0_i32; n as usize];
-    for k in 0_usize..n as usize {
-        let i = k / rows;
-        let j = k % rows;
-        x[k] = matrix[(i, j)];
-    }
-
-    match header.0.bits_per_sample {
-        8 => {
-            let mut v = Vec::with_capacity(n as usize);
-            for i in 0_usize..n as usize {
-                v[i] = x[i] as u8;
-            }
-            WavData::Eight(v)
-        },
-        16 => {
-            let mut v = vec![0; n as usize];
-            for i in 0_usize..n as usize {
-                v[i] = x[i] as i16;
-            }
-            WavData::Sixteen(v)
-        },
-        24 => {
-            WavData::TwentyFour(x)
-        },
+    let bits = header.0.bits_per_sample;
+    let (lo, hi) = sample_range(Some(bits));
+    let x: Vec<i64> = interleave(planes, header.1, layout).iter()
+        .map(|&x| (x.round() as i64).max(lo).min(hi))
+        .collect();
+
+    match bits {
+        8 => WavData::Eight(x.iter().map(|&x| x as u8).collect()),
+        16 => WavData::Sixteen(x.iter().map(|&x| x as i16).collect()),
+        24 => WavData::TwentyFour(x.iter().map(|&x| (x as i32) << 8).collect()),
         _ => WavData::Empty
     }
 }
 
-fn remove_vectors<T>(vectors: &mut SVDVectors<T>, options: &Options) 
-    -> Result<(), Error>
-    where T: Scalar {
+/// Samples of a sound with float samples, see `interleave`.
+pub (crate) fn sound_from_float_planes(planes: &[DMatrix<f32>], 
+                                       header: (WavHeader, u32), 
+                                       layout: Layout) -> WavData {
+    WavData::ThirtyTwoFloat(interleave(planes, header.1, layout).iter()
+                                .map(|&x| x as f32).collect())
+}
+
+/// Divides the singular values of a file holding shifted 24-bit samples (see
+/// `FileHeader::shifted_samples`) by 256, so that its triplets are written
+/// back for samples at their value.
+fn unshift_values<T: RealField>(vectors: &mut SVDVectors<T>, 
+                                header: &FileHeader) {
+    if header.shifted_samples() {
+        let scale: T = nalgebra::convert(256.0);
+        for triplet in vectors.iter_mut() {
+            triplet.0 /= scale;
+        }
+    }
+}
+
+fn remove_vectors<T>(vectors: &mut SVDVectors<T>, options: &Options, 
+                     write: WriteVectors<T>) -> Result<(), Error>
+    where T: RealField {
     
-    let h = vectors.len();
-    let w = vectors[0].1.len() * vectors[0].2.len();
-    let n2 = options.n_with(w, h)?;
-    if n2 > h {
+    let n = vectors.len();
+    let energy = energy_of(vectors);
+    let n2 = if options.policy.targets_error() {
+        options.n_for_error(vectors, energy)?
+    } else {
+        options.n_with(vectors[0].1.len(), vectors[0].2.len())?
+    };
+    if n2 > n {
         return Err(Error::NotEnoughVectorsInSource)
     }
 
+    let values = singular_values(vectors);
     vectors.truncate(n2);
+    let n3 = options.n_for_size(vectors, write)?;
+    plot_if_asked(&values, n3, energy, options)?;
+    vectors.truncate(n3);
+    report_energy(energy_of(vectors), energy);
     Ok(())
 }
+
+#[allow(dead_code, unused_imports)]
+mod tests {
+    use super::{Refinements, decode, recompute_matrix_f64, sound_from_planes, 
+                sound_from_float_planes, read_quantized};
+    use crate::{
+        read::FileReader,
+        tile::{Tiling, write_tiles},
+        write::FileWriter,
+        encode::{Options, CompressionPolicy, encode, write_vectors_header, 
+                 write_payload, write_vectors_f64, matrices_from_sound_data,
+                 sound_planes, write_quantized},
+        header::{FileHeader, QUANTIZATION_BITS},
+        colour::ColourSpace,
+        layout::Layout,
+    };
+    use nalgebra::{DMatrix, DVector};
+    use image::{Rgba, RgbaImage};
+    use wav::{BitDepth as WavData, Header as WavHeader};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_sound_channels() {
+        for &channels in [1_u16, 2, 6].iter() {
+            let c = channels as usize;
+            let samples: Vec<i16> = (0..(101 * c)).map(|k| {
+                // every channel has its own signal
+                ((k % c) as i16 + 1) * 1000 - (k / c) as i16 * ((k % c) as i16 - 2)
+            }).collect();
+            let mut planes: Vec<DMatrix<i32>> = 
+                matrices_from_sound_data(&samples, c, Layout::Square);
+            assert_eq!(planes.len(), c);
+            for (ch, m) in planes.iter().enumerate() {
+                assert_eq!(m.shape(), (10, 11));
+                assert_eq!(m[(0, 1)], samples[c + ch] as i32);
+            }
+
+            let space = if c == 2 { ColourSpace::MidSide } else { ColourSpace::Rgb };
+            space.from_rgb(&mut planes);
+            space.to_rgb(&mut planes);
+            let header = WavHeader::new(1, channels, 8000, 16);
+            match sound_from_planes(&planes, (header, samples.len() as u32),
+                                    Layout::Square) {
+                WavData::Sixteen(v) => assert_eq!(v, samples),
+                _ => panic!("wrong bit depth"),
+            }
+
+            // float samples are kept as they are
+            let floats: Vec<f32> = samples.iter().map(|&x| x as f32 / 32768.0)
+                                                 .collect();
+            let planes: Vec<DMatrix<f32>> = 
+                matrices_from_sound_data(&floats, c, Layout::Square);
+            let header = WavHeader::new(3, channels, 8000, 32);
+            match sound_from_float_planes(&planes, (header, floats.len() as u32),
+                                          Layout::Square) {
+                WavData::ThirtyTwoFloat(v) => assert_eq!(v, floats),
+                _ => panic!("wrong bit depth"),
+            }
+
+            // so are the integer ones in a trajectory matrix
+            let layout = Layout::Hankel(16);
+            let planes: Vec<DMatrix<i32>> = 
+                matrices_from_sound_data(&samples, c, layout);
+            assert_eq!(planes[0].shape(), (86, 16));
+            let header = WavHeader::new(1, channels, 8000, 16);
+            match sound_from_planes(&planes, (header, samples.len() as u32),
+                                    layout) {
+                WavData::Sixteen(v) => assert_eq!(v, samples),
+                _ => panic!("wrong bit depth"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_sound_24_bits() {
+        let values: Vec<i32> = (0..(2 * 333)).map(|k| match k {
+            0 => -(1 << 23),
+            1 => (1 << 23) - 1,
+            _ => (k * 7919) % (1 << 23) - (1 << 22),
+        }).collect();
+        let header = WavHeader::new(1, 2, 48000, 24);
+        let mut file = Cursor::new(Vec::new());
+        let shifted = values.iter().map(|&x| x << 8).collect();
+        wav::write(header, WavData::TwentyFour(shifted), &mut file).unwrap();
+        file.set_position(0);
+        let (header, data) = wav::read(&mut file).unwrap();
+
+        // the matrices hold the values of the samples
+        let layout = Layout::Frames(16);
+        let planes = sound_planes(&data, 2, layout).unwrap();
+        assert_eq!((planes[0][(0, 0)], planes[1][(0, 0)]), (values[0], values[1]));
+        assert_eq!(planes[0][(1, 0)], values[32]);
+
+        let wav = Some((header, values.len() as u32));
+        let raw = sound_from_planes(&planes, wav.unwrap(), layout);
+        let mut file = Cursor::new(Vec::new());
+        wav::write(header, raw, &mut file).unwrap();
+        file.set_position(0);
+        match (wav::read(&mut file).unwrap().1, &data) {
+            (WavData::TwentyFour(a), WavData::TwentyFour(b)) => assert_eq!(&a, b),
+            _ => panic!("wrong bit depth"),
+        }
+
+        // files of previous versions hold the samples as `wav` keeps them
+        let mut options = Options::default();
+        options.is_wav = true;
+        options.planar = true;
+        options.aggregator = None;
+        options.bits_per_sample = Some(24);
+        let mut file_header = FileHeader::from_options(&options, wav);
+        assert!(!file_header.shifted_samples());
+        file_header.version = 9;
+        assert!(file_header.shifted_samples());
+    }
+
+    #[test]
+    fn test_quantization() {
+        for &bits in QUANTIZATION_BITS.iter() {
+            let q_max = ((1_i32 << (bits - 1)) - 1) as f64;
+            for &n in [1_usize, 3, 7, 101].iter() {
+                let values: Vec<f64> = (0..n).map(|i| match i {
+                    0 => -1.0,
+                    1 => 1.0,
+                    _ => ((i * 37) % 101) as f64 / 50.0 - 1.0,
+                }).collect();
+                let mut fw = FileWriter::in_memory();
+                write_quantized(&mut fw, values.iter().cloned(), bits).unwrap();
+                fw.write_u8(0xab).unwrap();
+                let bytes = fw.into_bytes();
+                assert_eq!(bytes.len(), (n * bits as usize + 7) / 8 + 1);
+
+                let mut fr = FileReader::from_bytes(bytes);
+                let read = read_quantized(&mut fr, n, bits).unwrap();
+                for (x, y) in values.iter().zip(read.iter()) {
+                    assert!((x - y).abs() <= 0.5 / q_max + 1e-12);
+                }
+                // the bounds are exact
+                assert_eq!(read[0], -1.0);
+                assert!(n == 1 || read[1] == 1.0);
+                // the padding of the last byte is skipped
+                assert_eq!(fr.read_u8().unwrap(), 0xab);
+            }
+        }
+
+        // fields of any width follow each other without padding
+        let fields = [(5_u32, 3_u8), (0xfff, 12), (1, 1), (0xbeef, 16), (0, 7)];
+        let mut fw = FileWriter::in_memory();
+        for &(x, n) in fields.iter() {
+            fw.write_bits(x, n).unwrap();
+        }
+        fw.flush_bits().unwrap();
+        let bytes = fw.into_bytes();
+        assert_eq!(bytes.len(), 5);
+        let mut fr = FileReader::from_bytes(bytes);
+        for &(x, n) in fields.iter() {
+            assert_eq!(fr.read_bits(n).unwrap(), x);
+        }
+    }
+
+    #[test]
+    fn test_planar() {
+        let temp = |name: &str| {
+            std::env::temp_dir().join(name).to_str().unwrap().to_string()
+        };
+        let (input, output, decoded) = (temp("svd-img-planar.png"), 
+                                        temp("svd-img-planar.svd"),
+                                        temp("svd-img-planar-decoded.png"));
+        let image = RgbaImage::from_fn(13, 9, |i, j| {
+            Rgba([(i * 19) as u8, (j * 27) as u8, (i * j * 7 % 256) as u8,
+                  255 - (i * j) as u8])
+        });
+        image.save(&input).unwrap();
+
+        for &with_alpha in [false, true].iter() {
+            for &lossless in [false, true].iter() {
+                let mut options = Options::default();
+                options.aggregator = None;
+                options.planar = true;
+                options.with_alpha = with_alpha;
+                options.lossless = lossless;
+                // every triplet of each plane
+                options.policy = CompressionPolicy::with_number(9);
+                encode(&input, &output, &mut options).unwrap();
+                decode(&output, &decoded, &mut Options::default()).unwrap();
+
+                let result = image::open(&decoded).unwrap();
+                assert_eq!(result.color().has_alpha(), with_alpha);
+                let result = result.into_rgba8();
+                assert_eq!(result.dimensions(), image.dimensions());
+                let tolerance = if lossless { 0 } else { 1 };
+                for (a, b) in image.pixels().zip(result.pixels()) {
+                    for c in 0..3 {
+                        assert!((a[c] as i32 - b[c] as i32).abs() <= tolerance);
+                    }
+                    let alpha = if with_alpha { a[3] as i32 } else { 255 };
+                    assert!((alpha - b[3] as i32).abs() <= tolerance);
+                }
+            }
+        }
+        for file in [input, output, decoded].iter() {
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_refinements() {
+        let mut options = Options::default();
+        options.aggregator = None;
+        options.quantization = Some(12);
+        options.entropy_coding = true;
+        let header = FileHeader::from_options(&options, None);
+
+        let vectors = vec![
+            (300.0, DVector::from_fn(4, |i, _| 0.5 - 0.25 * i as f64),
+                    DVector::from_element(6, 0.4)),
+            (20.0, DVector::from_element(4, 0.5),
+                   DVector::from_fn(6, |i, _| 0.1 * i as f64 - 0.2)),
+        ];
+        let mut fw = FileWriter::in_memory();
+        write_vectors_header(&mut fw, &vectors, &header).unwrap();
+        write_payload(&mut fw, &header, 
+                      |w| write_vectors_f64(w, &vectors, &header)).unwrap();
+
+        let mut fr = FileReader::from_bytes(fw.into_bytes());
+        let header = FileHeader::read(&mut fr).unwrap();
+        let refined: Vec<Vec<DMatrix<i32>>> = Refinements::new(fr, &header)
+            .unwrap().map(|m| m.unwrap()).collect();
+        assert_eq!(refined.len(), 2);
+        for (k, m) in refined.iter().enumerate() {
+            let expected = recompute_matrix_f64(&vectors[..k + 1].to_vec())
+                .unwrap();
+            assert!((&m[0] - expected).amax() <= 1);
+        }
+
+        // the tiles are refined together, even when their ranks differ
+        options.tile_size = Some(4);
+        let header = FileHeader::from_options(&options, None);
+        let tiling = Tiling::new(4, 6, 4, 4);
+        let columns = |n: usize, col: usize, width: usize| {
+            vectors[..n].iter().map(|(sv, u, v)| {
+                (*sv, u.clone(), v.rows(col, width).into_owned())
+            }).collect::<Vec<_>>()
+        };
+        let tiles = vec![columns(2, 0, 4), columns(1, 4, 2)];
+        let mut fw = FileWriter::in_memory();
+        header.write(&mut fw).unwrap();
+        write_tiles(&mut fw, &header, &tiling, &tiles, write_vectors_f64)
+            .unwrap();
+
+        let mut fr = FileReader::from_bytes(fw.into_bytes());
+        let header = FileHeader::read(&mut fr).unwrap();
+        let refined: Vec<Vec<DMatrix<i32>>> = Refinements::new(fr, &header)
+            .unwrap().map(|m| m.unwrap()).collect();
+        assert_eq!(refined.len(), 2);
+        let first = recompute_matrix_f64(&vectors[..1].to_vec()).unwrap();
+        let full = recompute_matrix_f64(&vectors).unwrap();
+        assert!((&refined[0][0] - &first).amax() <= 1);
+        assert!((refined[1][0].columns(0, 4) - full.columns(0, 4)).amax() <= 1);
+        assert!((refined[1][0].columns(4, 2) - first.columns(4, 2)).amax() <= 1);
+
+        // every item holds all the planes, a plane without triplets being 0
+        options.tile_size = None;
+        options.planar = true;
+        let header = FileHeader::from_options(&options, None);
+        let tiling = Tiling::new(4, 6, 4, 6).with_planes(3);
+        let planes = vec![columns(2, 0, 6), columns(1, 0, 6), vec![]];
+        let mut fw = FileWriter::in_memory();
+        header.write(&mut fw).unwrap();
+        write_tiles(&mut fw, &header, &tiling, &planes, write_vectors_f64)
+            .unwrap();
+
+        let mut fr = FileReader::from_bytes(fw.into_bytes());
+        let header = FileHeader::read(&mut fr).unwrap();
+        let refined: Vec<Vec<DMatrix<i32>>> = Refinements::new(fr, &header)
+            .unwrap().map(|m| m.unwrap()).collect();
+        assert_eq!(refined.len(), 2);
+        assert!(refined.iter().all(|planes| planes.len() == 3));
+        assert!((&refined[1][0] - &full).amax() <= 1);
+        assert!((&refined[1][1] - &first).amax() <= 1);
+        assert_eq!(refined[1][2], DMatrix::zeros(4, 6));
+    }
+}
@@ -2,15 +2,41 @@ use crate::aggregate;
 
 use super::{
     Error,
+    read::FileReader,
     write::FileWriter,
     aggregate::Aggregator,
+    colour::ColourSpace,
+    layout::{Layout, MAX_HANKEL_VALUES},
+    header::{FileHeader, MAX_DIMENSION},
+    entropy,
+    svd::{truncated_svd, SVDMethod, Convergence},
+    plot::{plot_spectrum, error_heatmap},
+    residual::{residual, write_residual},
+    correction::{Correction, sample_range},
+    tile::{Tiling, reduce_tiles, refine_tiles, write_tiles, 
+           write_tiles_header, read_tiles_header, tiles_size, 
+           recompute_tiles},
+    segment::encode_segments,
+
+    metrics::{Quality, Samples, input_samples, decoded_samples, float_samples},
 
     decode::{
+        ReadVectors,
+        read_file_header,
+        read_file_dimensions,
+        read_payload,
+        options_from_header,
+        drop_residual,
+        read_vectors_f64,
+        read_vectors_f32,
         recompute_matrix_f64,
         recompute_matrix_f32,
         imgbuf_from_matrix_rgb,
         imgbuf_from_matrix_rgba,
-        sound_from_matrix, 
+        imgbuf_from_planes_rgb,
+        imgbuf_from_planes_rgba,
+        sound_from_planes, 
+        sound_from_float_planes,
     },
 };
 
@@ -30,9 +56,37 @@ use wav::{
     BitDepth as WavData,
 };
 
-use nalgebra::{ DMatrix, DVector, Scalar };
+use nalgebra::{ DMatrix, DVector, RealField, Scalar };
 
 pub type SVDVectors<T> = Vec<(T, DVector<T>, DVector<T>)>;
+/// Writes the triplets without compressing them, see `write_vectors_f64`.
+pub type WriteVectors<T> = 
+    fn(&mut FileWriter, &SVDVectors<T>, &FileHeader) -> Result<(), Error>;
+
+/// Values of the decomposed matrices: integers for images and most sounds,
+/// floats for sounds with float samples.
+pub trait Sample: Scalar + Copy + Into<f64> {
+    const ZERO: Self;
+    fn to_f32(self) -> f32;
+    /// Value of a sample recomputed as `x`.
+    fn from_f64(x: f64) -> Self;
+    fn from_f32(x: f32) -> Self;
+}
+
+impl Sample for i32 {
+    const ZERO: Self = 0;
+    fn to_f32(self) -> f32 {  f32_from_i32_bad(self)  }
+    fn from_f64(x: f64) -> Self {  x.round() as i32  }
+    fn from_f32(x: f32) -> Self {  x.round() as i32  }
+}
+
+impl Sample for f32 {
+    const ZERO: Self = 0.0;
+    fn to_f32(self) -> f32 {  self  }
+    fn from_f64(x: f64) -> Self {  x as f32  }
+    fn from_f32(x: f32) -> Self {  x  }
+}
+
 pub trait Printable {
     fn to_string(&self) -> String;
 }
@@ -42,11 +96,41 @@ pub struct Options {
     pub use_f64: bool,
     pub eps: f32,
     pub n_iter: usize,
+    pub svd_method: SVDMethod,
     pub original_file_size: u64,
 
-    pub use_aggregate: bool,
     pub aggregator: Option<Box<dyn Aggregator>>,
     pub with_alpha: bool,
+    /// Number of bits used to store each element of the singular vectors, 
+    /// `None` to store them as floats.
+    pub quantization: Option<u8>,
+    pub entropy_coding: bool,
+    /// Size of the square tiles the matrix is split in, `None` to decompose
+    /// the whole matrix at once.
+    pub tile_size: Option<usize>,
+    /// Whether each channel of the image is decomposed separately.
+    pub planar: bool,
+    /// Colour space of the planes in planar mode.
+    pub colour_space: ColourSpace,
+    /// Whether the difference between the input and the approximation is
+    /// stored too, see `residual`.
+    pub lossless: bool,
+    /// Largest difference allowed between a decoded sample and the input
+    /// one, a correction being stored where the approximation is further,
+    /// see `correction`.
+    pub tolerance: Option<u32>,
+    /// Layout of the samples of each channel of a sound in its matrix.
+    pub layout: Layout,
+    /// Number of frames of the segments a sound is split in, each one being
+    /// decomposed on its own (see `segment`), `None` to decompose it at once.
+    pub segment_length: Option<usize>,
+    /// Number of triplets (of each tile) used by `decode`, all of them if 
+    /// `None`.
+    pub rank: Option<usize>,
+    /// PNG in which the singular values are drawn, see `plot_spectrum`.
+    pub spectrum_plot: Option<String>,
+    /// PNG in which the error of each pixel is drawn, see `error_heatmap`.
+    pub error_heatmap: Option<String>,
 
     pub is_wav: bool, 
     pub is_reduce: bool,
@@ -55,73 +139,487 @@ pub struct Options {
 
 pub enum CompressionPolicy {
     Number(usize),
-    Ratio(u8)
+    Ratio(u8),
+    /// Keeps the fewest triplets reaching the given quality.
+    Quality(QualityTarget),
+    /// Keeps the fewest triplets whose squared singular values sum to at 
+    /// least the given fraction (in `[0, 1]`) of the squared norm of the 
+    /// matrix.
+    Energy(f64),
+    /// Keeps as many triplets as possible in a file of at most the given 
+    /// number of bytes.
+    Size(u64)
+}
+
+pub enum QualityTarget {
+    /// Peak signal to noise ratio, in dB, see `Options::peak`.
+    Psnr(f64),
+    /// Frobenius norm of the error relative to the one of the matrix.
+    RelativeError(f64)
+}
+
+/// What `encode` and `fuck_up` tell about their result.
+#[allow(dead_code)]
+pub struct Summary {
+    /// How well the triplets used are converged.
+    pub convergence: Convergence,
+    /// Distortion of the stored approximation with respect to the input,
+    /// once corrected if the file is near-lossless. Decoding a lossless file
+    /// gives back the input, its stored difference being added to it.
+    pub quality: Quality,
 }
 
 /// Encodes image file in `input` to vector file `output`, with given 
-/// `options`.
+/// `options`. Returns how well the stored triplets are converged and the
+/// quality of the file once decoded.
 pub fn encode(input: &str, output: &str, options: &mut Options) 
-    -> Result<(), Error> {
+    -> Result<Summary, Error> {
 
-    let (matrix, header) = read_matrix(input, options)?;
+    if options.segment_length.is_some() {
+        return encode_segments(input, output, options);
+    }
+    // neither the residual nor the correction is part of the size that the
+    // triplets are fit in
+    if let CompressionPolicy::Size(_) = options.policy {
+        if options.lossless {
+            return Err(Error::UnsupportedMode("maximum size of a lossless \
+                                               file"));
+        }
+        if options.tolerance.is_some() {
+            return Err(Error::UnsupportedMode("maximum size of a \
+                                               near-lossless file"));
+        }
+    }
+
+    let (planes, header) = match read_input(input, options)? {
+        (Input::Integer(planes), header) => (planes, header),
+        (Input::Float(planes), Some(h)) => 
+            return encode_float(input, output, &planes, h, options),
+        (Input::Float(_), None) => unreachable!(),
+    };
 
     let mut fw = FileWriter::from_name(output)?;
+    let file_header = FileHeader::from_options(options, header);
+
+    let (recomputed, convergence) = 
+        write_approximation(&mut fw, &file_header, &planes, options)?;
+
+    let original = input_samples(input, options)?;
+    let mut decoded = decoded_samples(&recomputed, options, header)?;
+    if let Some(t) = file_header.tolerance {
+        let correction = Correction::between(&original, &decoded, t);
+        println!("{} samples corrected to be within {} of the input", 
+                 correction.n_corrected(), t);
+        correction.write(&mut fw)?;
+        let (lo, hi) = sample_range(header.map(|h| h.0.bits_per_sample));
+        correction.apply_to_samples(&mut decoded, lo, hi);
+    }
+    let quality = Quality::between(&original, &decoded);
+    report_stored_quality(&quality, &file_header);
+
+    if file_header.lossless {
+        // the residual is added once the planes are back to r, g, b
+        let mut decoded = recomputed;
+        let original = if options.planar && options.is_wav {
+            // mid and side give back the exact channels
+            options.colour_space.to_rgb(&mut decoded);
+            let mut channels = planes;
+            options.colour_space.to_rgb(&mut channels);
+            channels
+        } else if options.planar {
+            options.colour_space.to_rgb(&mut decoded);
+            image_planes(read_image_file(input)?.into_rgba8(), 
+                         options.with_alpha)
+        } else {
+            planes
+        };
+        write_residual(&mut fw, &residual(&original, &decoded))?;
+    }
 
-    if options.use_f64 {
-        let vectors: SVDVectors<f64> = matrix_reduce_f64(&matrix, options)?;
+    Ok(Summary { convergence, quality })
+}
+
+/// `encode` for a sound with float samples, which cannot be lossless.
+fn encode_float(input: &str, output: &str, planes: &[DMatrix<f32>], 
+                header: (WavHeader, u32), options: &mut Options)
+    -> Result<Summary, Error> {
+
+    if options.lossless || options.tolerance.is_some() {
+        return Err(Error::UnsupportedMode("lossless float samples"));
+    }
+    let mut fw = FileWriter::from_name(output)?;
+    let file_header = FileHeader::from_options(options, Some(header));
+
+    let (recomputed, convergence) = 
+        write_approximation(&mut fw, &file_header, planes, options)?;
+
+    let decoded = float_samples(&recomputed, header, options.layout);
+    let quality = Quality::between(&input_samples(input, options)?, &decoded);
+    report_quality(&quality);
+    Ok(Summary { convergence, quality })
+}
+
+/// Decomposes the `planes` and writes in `fw` the triplets kept, after
+/// `file_header`. Returns the planes as they will be decoded (that is, from 
+/// the stored triplets) and how well these triplets are converged.
+fn write_approximation<T: Sample>(fw: &mut FileWriter, file_header: &FileHeader,
+                                  planes: &[DMatrix<T>], options: &Options)
+    -> Result<(Vec<DMatrix<T>>, Convergence), Error> {
+
+    if file_header.tiled {
+        file_header.write(fw)?;
+        write_tiled_approximation(fw, file_header, planes, options)
+    }
+    else if options.use_f64 {
+        let (vectors, convergence) = matrix_reduce_f64(&planes[0], options)?;
         // println!("{}", vectors.to_string());
-        let rec = recompute_matrix_f64(&vectors)?;
-        println!("recomputed: {}", rec);
-        
-        write_vectors_header(&mut fw, &vectors, &options, header)?;
-        write_vectors_f64(&mut fw, &vectors)?;
+        write_vectors_header(fw, &vectors, file_header)?;
+        write_payload(fw, file_header, 
+                      |w| write_vectors_f64(w, &vectors, file_header))?;
+        let stored = stored_vectors(&vectors, file_header, write_vectors_f64,
+                                    read_vectors_f64)?;
+        Ok((vec![recompute_matrix_f64(&stored)?], convergence))
     }
     else {
-        let vectors: SVDVectors<f32> = matrix_reduce_f32(&matrix, options)?;
+        let (vectors, convergence) = matrix_reduce_f32(&planes[0], options)?;
         // println!("{}", vectors.to_string());
-        write_vectors_header(&mut fw, &vectors, &options, header)?;
-        write_vectors_f32(&mut fw, &vectors)?;
+        write_vectors_header(fw, &vectors, file_header)?;
+        write_payload(fw, file_header, 
+                      |w| write_vectors_f32(w, &vectors, file_header))?;
+        let stored = stored_vectors(&vectors, file_header, write_vectors_f32,
+                                    read_vectors_f32)?;
+        Ok((vec![recompute_matrix_f32(&stored)?], convergence))
     }
+}
 
-    Ok(())
+/// Splits the `planes` in tiles and writes their triplets in `fw`, that is 
+/// the tiled body that follows `file_header` (or one segment of it, see
+/// `segment`). Returns the planes as they will be decoded.
+pub(crate) fn write_tiled_approximation<T: Sample>(fw: &mut FileWriter, 
+                                                   file_header: &FileHeader,
+                                                   planes: &[DMatrix<T>], 
+                                                   options: &Options)
+    -> Result<(Vec<DMatrix<T>>, Convergence), Error> {
+
+    let tiling = tiling_of(planes, options);
+    let tiles = tiling.split_planes(planes);
+    if options.use_f64 {
+        let (tiles, convergence) = 
+            tiles_reduce_f64(tiles, &tiling, options)?;
+        write_tiles(fw, file_header, &tiling, &tiles, write_vectors_f64)?;
+        let stored = stored_tiles(&tiles, file_header, write_vectors_f64,
+                                  read_vectors_f64)?;
+        Ok((recompute_tiles(&tiling, &stored, recompute_matrix_f64)?, 
+            convergence))
+    } else {
+        let (tiles, convergence) = 
+            tiles_reduce_f32(tiles, &tiling, options)?;
+        write_tiles(fw, file_header, &tiling, &tiles, write_vectors_f32)?;
+        let stored = stored_tiles(&tiles, file_header, write_vectors_f32,
+                                  read_vectors_f32)?;
+        Ok((recompute_tiles(&tiling, &stored, recompute_matrix_f32)?, 
+            convergence))
+    }
 }
 
+/// Approximates `input` and writes the result in `output`. Returns how well
+/// the triplets used are converged and the quality of the result.
 pub fn fuck_up(input: &str, output: &str, options: &mut Options) 
-    -> Result<(), Error> {
-
-    let (matrix, header) = read_matrix(input, options)?;
-    
-    let recomputed = if options.use_f64 {
-        let vectors: SVDVectors<f64> = matrix_reduce_f64(&matrix, options)?;
-        recompute_matrix_f64(&vectors)?
-    }
-    else {
-        let vectors: SVDVectors<f32> = matrix_reduce_f32(&matrix, options)?;
-        recompute_matrix_f32(&vectors)?
+    -> Result<Summary, Error> {
+
+    let (planes, header) = match read_input(input, options)? {
+        (Input::Integer(planes), header) => (planes, header),
+        (Input::Float(planes), Some(h)) => {
+            let (recomputed, convergence) = approximate(&planes, options)?;
+            let decoded = float_samples(&recomputed, h, options.layout);
+            let quality = Quality::between(&input_samples(input, options)?, 
+                                           &decoded);
+            report_quality(&quality);
+            let raw_data = sound_from_float_planes(&recomputed, h, 
+                                                   options.layout);
+            let mut out_file = File::create(Path::new(output))?;
+            wav::write(h.0, raw_data, &mut out_file)?;
+            return Ok(Summary { convergence, quality });
+        },
+        (Input::Float(_), None) => unreachable!(),
     };
+    
+    let (mut recomputed, convergence) = approximate(&planes, options)?;
 
+    let original = input_samples(input, options)?;
+    let decoded = decoded_samples(&recomputed, options, header)?;
+    let quality = Quality::between(&original, &decoded);
+    report_quality(&quality);
+    heatmap_if_asked(&original, &decoded, options)?;
+
+    if options.planar {
+        options.colour_space.to_rgb(&mut recomputed);
+    }
     if options.is_wav {
         let h = header.unwrap();
-        let raw_data = sound_from_matrix(&matrix, h);
+        let raw_data = sound_from_planes(&recomputed, h, options.layout);
         let mut out_file = File::create(Path::new(output))?;
         wav::write(h.0, raw_data, &mut out_file)?;
 
+    } else if options.planar {
+        if options.with_alpha {
+            imgbuf_from_planes_rgba(&recomputed).save(output).unwrap();
+        } else {
+            imgbuf_from_planes_rgb(&recomputed).save(output).unwrap();
+        }
     } else {
         if options.with_alpha {
-            let imgbuf = imgbuf_from_matrix_rgba(&recomputed, &options.aggregator)?;
+            let imgbuf = imgbuf_from_matrix_rgba(&recomputed[0], &options.aggregator)?;
             imgbuf.save(output).unwrap();
         } else {
-            let imgbuf = imgbuf_from_matrix_rgb(&recomputed, &options.aggregator)?;
+            let imgbuf = imgbuf_from_matrix_rgb(&recomputed[0], &options.aggregator)?;
             imgbuf.save(output).unwrap();
         }
     }
 
-    Ok(())
+    Ok(Summary { convergence, quality })
+}
 
+/// Approximation of the `planes` with the triplets `options` ask for, and
+/// how well these triplets are converged.
+fn approximate<T: Sample>(planes: &[DMatrix<T>], options: &Options)
+    -> Result<(Vec<DMatrix<T>>, Convergence), Error> {
+
+    if options.tile_size.is_some() || options.planar {
+        let tiling = tiling_of(planes, options);
+        let tiles = tiling.split_planes(planes);
+        if options.use_f64 {
+            let (tiles, convergence) = 
+                tiles_reduce_f64(tiles, &tiling, options)?;
+            Ok((recompute_tiles(&tiling, &tiles, recompute_matrix_f64)?, 
+                convergence))
+        } else {
+            let (tiles, convergence) = 
+                tiles_reduce_f32(tiles, &tiling, options)?;
+            Ok((recompute_tiles(&tiling, &tiles, recompute_matrix_f32)?, 
+                convergence))
+        }
+    }
+    else if options.use_f64 {
+        let (vectors, convergence) = matrix_reduce_f64(&planes[0], options)?;
+        Ok((vec![recompute_matrix_f64(&vectors)?], convergence))
+    }
+    else {
+        let (vectors, convergence) = matrix_reduce_f32(&planes[0], options)?;
+        Ok((vec![recompute_matrix_f32(&vectors)?], convergence))
+    }
+}
+
+/// Appends to the compressed file `input` the first singular triplets of the
+/// difference between `original` and the approximation stored in `input`,
+/// as many as the compression policy asks for the whole file, and writes the
+/// result in `output`. The triplets already stored are copied as they are,
+/// so that the body of `output` starts with the same bytes (before entropy
+/// coding).
+pub fn refine(input: &str, output: &str, original: &str, 
+              options: &mut Options) -> Result<(), Error> {
+
+    let (mut header, mut fr) = read_file_header(input)?;
+    if header.shifted_samples() {
+        return Err(Error::UnsupportedMode("refinement of a 24-bit sound \
+                                           of a previous version"));
+    }
+    options_from_header(options, &header);
+    drop_residual(&mut header);
+    if header.tiled {
+        return refine_tiled(fr, output, original, &header, options);
+    }
+    let (n, h, w) = read_file_dimensions(&mut fr, &header)?;
+    let size = n * header.triplet_size(h, w);
+    let stored = read_payload(&mut fr, &header, size, 
+                              |r| Ok(r.read_bytes(size)?))?;
+
+    let (planes, wav) = read_matrix(original, options)?;
+    let matrix = &planes[0];
+    if planes.len() != 1 || matrix.shape() != (h, w) || 
+       wav.map(|x| x.1) != header.wav.map(|x| x.1) {
+        return Err(Error::DimensionMismatch);
+    }
+
+    let mut fw = FileWriter::from_name(output)?;
+    let mut fr = FileReader::from_bytes(stored.clone());
+    if header.use_f64 {
+        let old = read_vectors_f64(&mut fr, &header, n, h, w)?;
+        let m = DMatrix::from_fn(h, w, |i, j| matrix[(i, j)] as f64);
+        let new = residual_vectors(m, &old, options, options.eps.into(), 
+                                   write_vectors_f64)?;
+        write_refined(&mut fw, &header, &stored, old, &new, write_vectors_f64)
+    } else {
+        let old = read_vectors_f32(&mut fr, &header, n, h, w)?;
+        let m = DMatrix::from_fn(h, w, |i, j| f32_from_i32_bad(matrix[(i, j)]));
+        let new = residual_vectors(m, &old, options, options.eps, 
+                                   write_vectors_f32)?;
+        write_refined(&mut fw, &header, &stored, old, &new, write_vectors_f32)
+    }
 }
 
-fn read_matrix(input: &str, options: &mut Options)
-    -> Result<(DMatrix<i32>, Option<(WavHeader, u32)>), Error> {
+/// Refinement of a tiled file, `fr` being right after its `header`: the
+/// triplets are appended to the ones of every tile, see `refine_tiles`.
+fn refine_tiled(mut fr: FileReader, output: &str, original: &str, 
+                header: &FileHeader, options: &mut Options) 
+    -> Result<(), Error> {
+
+    let (tiling, ranks) = read_tiles_header(&mut fr, header)?;
+    let size = tiles_size(&tiling, &ranks, header);
+    let stored = read_payload(&mut fr, header, size, 
+                              |r| Ok(r.read_bytes(size)?))?;
+
+    let (planes, wav) = read_matrix(original, options)?;
+    if planes.len() != tiling.planes || 
+       planes[0].shape() != (tiling.height, tiling.width) ||
+       wav.map(|x| x.1) != header.wav.map(|x| x.1) {
+        return Err(Error::DimensionMismatch);
+    }
+    let tiles = tiling.split_planes(&planes);
+
+    let mut fw = FileWriter::from_name(output)?;
+    let mut fr = FileReader::from_bytes(stored.clone());
+    if header.use_f64 {
+        let mut old = Vec::with_capacity(ranks.len());
+        for (&(h, w), &n) in tiling.blocks().iter().zip(ranks.iter()) {
+            old.push(read_vectors_f64(&mut fr, header, n, h, w)?);
+        }
+        let tiles = tiles.into_iter().map(|t| t.map(|x| x.into())).collect();
+        let new = refine_tiles(tiles, &old, &tiling, options, 
+                               options.eps.into(), write_vectors_f64)?;
+        write_refined_tiles(&mut fw, header, &tiling, &stored, old, &new, 
+                            write_vectors_f64)
+    } else {
+        let mut old = Vec::with_capacity(ranks.len());
+        for (&(h, w), &n) in tiling.blocks().iter().zip(ranks.iter()) {
+            old.push(read_vectors_f32(&mut fr, header, n, h, w)?);
+        }
+        let tiles = tiles.into_iter().map(|t| t.map(|x| x.to_f32())).collect();
+        let new = refine_tiles(tiles, &old, &tiling, options, options.eps, 
+                               write_vectors_f32)?;
+        write_refined_tiles(&mut fw, header, &tiling, &stored, old, &new, 
+                            write_vectors_f32)
+    }
+}
+
+/// First singular triplets of `m` minus the approximation given by `old`,
+/// as many as needed for the whole file to follow the compression policy.
+fn residual_vectors<N>(m: DMatrix<N>, old: &SVDVectors<N>, options: &Options,
+                       eps: N, write: WriteVectors<N>) 
+    -> Result<SVDVectors<N>, Error>
+    where N: RealField
+    {
+    let (h, w) = m.shape();
+    let n = old.len();
+    let energy = nalgebra::try_convert::<N, f64>(m.norm_squared())
+        .unwrap_or(0.0);
+
+    let mut residual = m;
+    for (sv, u, v) in old.iter() {
+        residual.ger(-*sv, u, v, N::one());
+    }
+    let mut error = nalgebra::try_convert::<N, f64>(residual.norm_squared())
+        .unwrap_or(0.0);
+
+    let total = options.n_with(h, w)?.min(h.min(w));
+    if total <= n {
+        return Err(Error::NothingToRefine);
+    }
+    let (mut new, convergence) = truncated_svd(residual, total - n, 
+                                               &options.svd_method, eps,
+                                               options.n_iter)?;
+    report_convergence(&convergence, options);
+
+    if options.policy.targets_error() {
+        let allowed = options.allowed_error(energy, h * w)?;
+        let mut k = 0;
+        while k < new.len() && error > allowed {
+            let sv = nalgebra::try_convert::<N, f64>(new[k].0).unwrap_or(0.0);
+            error -= sv * sv;
+            k += 1;
+        }
+        new.truncate(k);
+    }
+
+    let mut all = old.clone();
+    all.extend(new.iter().cloned());
+    let kept = options.n_for_size(&all, write)?;
+    if kept <= n {
+        return Err(Error::NothingToRefine);
+    }
+    new.truncate(kept - n);
+
+    println!("Adding {} vectors to the {} stored ones.", new.len(), n);
+    Ok(new)
+}
+
+/// Writes the file holding the `old` triplets, whose bytes are `stored`, 
+/// followed by the `new` ones.
+fn write_refined<N>(fw: &mut FileWriter, header: &FileHeader, stored: &[u8],
+                    mut old: SVDVectors<N>, new: &SVDVectors<N>, 
+                    write: WriteVectors<N>) -> Result<(), Error>
+    where N: Scalar
+    {
+    old.extend(new.iter().cloned());
+    write_vectors_header(fw, &old, header)?;
+    write_payload(fw, header, |w| {
+        w.write_all(stored)?;
+        write(w, new, header)
+    })
+}
+
+/// Writes the tiled file holding the `old` triplets of every tile, whose
+/// bytes are `stored` one tile after the other, each followed by the `new`
+/// ones of the tile.
+fn write_refined_tiles<N>(fw: &mut FileWriter, header: &FileHeader, 
+                          tiling: &Tiling, stored: &[u8],
+                          mut old: Vec<SVDVectors<N>>, new: &[SVDVectors<N>],
+                          write: WriteVectors<N>) -> Result<(), Error>
+    where N: Scalar
+    {
+    let sizes: Vec<usize> = tiling.blocks().iter().zip(old.iter())
+        .map(|(&(h, w), v)| v.len() * header.triplet_size(h, w))
+        .collect();
+    for (vectors, added) in old.iter_mut().zip(new) {
+        vectors.extend(added.iter().cloned());
+    }
+    header.write(fw)?;
+    write_tiles_header(fw, tiling, &old)?;
+    write_payload(fw, header, |w| {
+        let mut start = 0;
+        for (size, added) in sizes.iter().zip(new) {
+            w.write_all(&stored[start..start + size])?;
+            write(w, added, header)?;
+            start += size;
+        }
+        Ok(())
+    })
+}
+
+/// What an input file is decomposed as.
+pub(crate) enum Input {
+    /// Values of an image, or samples of a sound stored as integers.
+    Integer(Vec<DMatrix<i32>>),
+    /// Samples of a sound stored as 32-bit floats.
+    Float(Vec<DMatrix<f32>>),
+}
+
+/// Reads the matrix to decompose, or one matrix per channel in planar mode,
+/// of an input that is not a sound with float samples (see `read_input`).
+pub(crate) fn read_matrix(input: &str, options: &mut Options)
+    -> Result<(Vec<DMatrix<i32>>, Option<(WavHeader, u32)>), Error> {
+
+    match read_input(input, options)? {
+        (Input::Integer(planes), header) => Ok((planes, header)),
+        (Input::Float(_), _) => 
+            Err(Error::UnsupportedMode("32-bit float samples")),
+    }
+}
+
+/// Reads the matrix to decompose, or one matrix per channel in planar mode,
+/// with the header of the sound if `input` is a WAV file.
+pub(crate) fn read_input(input: &str, options: &mut Options)
+    -> Result<(Input, Option<(WavHeader, u32)>), Error> {
 
     options.is_wav |= input.ends_with(".WAV") ||
                       input.ends_with(".wav");
@@ -130,15 +628,24 @@ fn read_matrix(input: &str, options: &mut Options)
     options.original_file_size = metadata.len();
 
     if !options.is_wav {
+        if options.layout != Layout::Square {
+            return Err(Error::UnsupportedMode("layout of an image"));
+        }
         let img = read_image_file(input)?;
-        if options.with_alpha {
+        if options.planar {
+            let mut planes = image_planes(img.into_rgba8(), options.with_alpha);
+            options.colour_space.from_rgb(&mut planes);
+            Ok((Input::Integer(planes), None))
+        } else if options.with_alpha {
             Ok(
-                (image_matrix_rgba(img.into_rgba8(), &options.aggregator), 
+                (Input::Integer(vec![image_matrix_rgba(img.into_rgba8(), 
+                                                       &options.aggregator)]), 
                  None)
             )
         } else {
             Ok(
-                (image_matrix_rgb(img.into_rgb8(), &options.aggregator), 
+                (Input::Integer(vec![image_matrix_rgb(img.into_rgb8(), 
+                                                      &options.aggregator)]), 
                  None)
             )
         }
@@ -148,21 +655,74 @@ fn read_matrix(input: &str, options: &mut Options)
     } else {
         let mut in_file = File::open(Path::new(input))?;
         let (header_small, sound_data) = wav::read(&mut in_file)?;
-        options.bits_per_sample = Some(header_small.bits_per_sample);
         let n = match &sound_data {
             WavData::Eight(x) => x.len(),
             WavData::Sixteen(x) => x.len(),
             WavData::TwentyFour(x) => x.len(),
+            WavData::ThirtyTwoFloat(x) => x.len(),
             _ => 0,
         };
 
-        Ok(
-            (sound_matrix(&sound_data).unwrap(), 
-             Some((header_small, n as u32)))
-        )
+        let channels = sound_options(&header_small, n, options)?;
+        let planes = if let WavData::ThirtyTwoFloat(d) = &sound_data {
+            Input::Float(matrices_from_sound_data(d, channels, options.layout))
+        } else {
+            let mut planes = sound_planes(&sound_data, channels, options.layout)
+                .ok_or(Error::UnsupportedMode("WAV bit depth"))?;
+            options.colour_space.from_rgb(&mut planes);
+            Input::Integer(planes)
+        };
+
+        Ok((planes, Some((header_small, n as u32))))
     }
 }
 
+/// Sets the `options` of a sound with the given header and `n_samples`
+/// samples, checking that they apply to it. Returns its number of channels.
+pub(crate) fn sound_options(header: &WavHeader, n_samples: usize, 
+                            options: &mut Options)
+    -> Result<usize, Error> {
+
+    options.bits_per_sample = Some(header.bits_per_sample);
+
+    // the channels are decomposed separately
+    let channels = header.channel_count.max(1) as usize;
+    options.planar = channels > 1;
+    match (options.colour_space, channels) {
+        (ColourSpace::Rgb, _) | (ColourSpace::MidSide, 2) => {},
+        (ColourSpace::MidSide, _) => 
+            return Err(Error::UnsupportedMode("mid/side of a sound \
+                                               that is not stereo")),
+        (ColourSpace::YCbCr, _) => 
+            return Err(Error::UnsupportedMode("YCbCr sound")),
+    }
+    if options.lossless && !options.layout.is_exact() {
+        return Err(Error::UnsupportedMode("lossless STFT layout"));
+    }
+    if header.bits_per_sample == 32 && options.colour_space != ColourSpace::Rgb {
+        return Err(Error::UnsupportedMode("mid/side of float samples"));
+    }
+
+    // the decoder rejects larger matrices
+    let mut frames = (n_samples / channels).max(1);
+    if let Some(l) = options.segment_length {
+        frames = frames.min(l);
+    }
+    let (rows, cols) = options.layout.shape(frames);
+    if rows > MAX_DIMENSION || cols > MAX_DIMENSION {
+        return Err(Error::UnsupportedMode("layout of a matrix of more than \
+                                           2^20 rows or columns"));
+    }
+    if let Layout::Hankel(_) = options.layout {
+        if rows * cols > MAX_HANKEL_VALUES {
+            return Err(Error::UnsupportedMode("trajectory matrix of more \
+                                               than 2^26 values (use shorter \
+                                               segments)"));
+        }
+    }
+    Ok(channels)
+}
+
 pub fn read_image_file(name: &str) -> Result<DynamicImage, Error> {
     match ImageReader::open(name) {
         Ok(img_data) => match img_data.decode() {
@@ -231,34 +791,58 @@ fn image_matrix_rgb(img: RgbImage, aggregator: &Option<Box<dyn Aggregator>>)
     }
 }
 
-fn sound_matrix(data: &WavData) -> Option<DMatrix<i32>>
-    {
+/// Returns one DMatrix<i32> per channel of the image, the alpha channel being
+/// kept only if `with_alpha`.
+fn image_planes(img: RgbaImage, with_alpha: bool) -> Vec<DMatrix<i32>> {
+    let dim = img.dimensions();
+    let n_channels = if with_alpha { 4 } else { 3 };
+
+    (0..n_channels).map(|c| {
+        DMatrix::from_fn(dim.0 as usize, dim.1 as usize, |i, j| {
+            img[(i as u32, j as u32)][c] as i32
+        })
+    }).collect()
+}
+
+/// Returns one DMatrix<i32> per channel of the sound, see 
+/// `matrices_from_sound_data`. 24-bit samples, that `wav` keeps in the 3
+/// upper bytes of an `i32`, are shifted back to their value.
+pub(crate) fn sound_planes(data: &WavData, channels: usize, layout: Layout)
+    -> Option<Vec<DMatrix<i32>>> {
 
     match data {
-        WavData::Eight(d) => Some(matrix_from_sound_data(&d)),
-        WavData::Sixteen(d) => Some(matrix_from_sound_data(&d)),
-        WavData::TwentyFour(d) => Some(matrix_from_sound_data(&d)),
+        WavData::Eight(d) => Some(matrices_from_sound_data(&d, channels, layout)),
+        WavData::Sixteen(d) => Some(matrices_from_sound_data(&d, channels, layout)),
+        WavData::TwentyFour(d) => {
+            let d: Vec<i32> = d.iter().map(|&x| x >> 8).collect();
+            Some(matrices_from_sound_data(&d, channels, layout))
+        },
         _ => None
     }
 }
 
-fn matrix_from_sound_data<T>(data: &[T]) -> DMatrix<i32>
-    where T: Scalar + Into<i32> + Copy
+/// Deinterleaves the samples of the `channels` channels of a sound and puts
+/// the ones of each channel in a matrix, as given by `layout`. A mono sound
+/// gives a single matrix.
+pub(crate) fn matrices_from_sound_data<T, S>(data: &[T], channels: usize,
+                                             layout: Layout) 
+    -> Vec<DMatrix<S>>
+    where T: Copy + Into<f64>, S: Sample
     {
 
     let n = data.len();
-    let rows = (n as f64).sqrt().round() as usize;
-    let cols = (n as f64 / rows as f64).ceil() as usize;
-
-    DMatrix::from_fn(rows, cols, |i, j| {
-        if i * rows + j < n {
-            data[i * rows + j].into()
-        } else { 0 }
-    })
+    let frames = (n + channels - 1) / channels;
+
+    (0..channels).map(|c| {
+        let samples: Vec<f64> = (0..frames).map(|k| {
+            data.get(k * channels + c).map_or(0.0, |&x| x.into())
+        }).collect();
+        layout.to_matrix(&samples).map(S::from_f64)
+    }).collect()
 }
 
-fn matrix_reduce_f64<T>(matrix: &DMatrix<T>, options: &Options)
-    -> Result<SVDVectors<f64>, Error>
+pub(crate) fn matrix_reduce_f64<T>(matrix: &DMatrix<T>, options: &Options)
+    -> Result<(SVDVectors<f64>, Convergence), Error>
     where T: Scalar + Into<f64> + Copy
     {
     
@@ -266,96 +850,162 @@ fn matrix_reduce_f64<T>(matrix: &DMatrix<T>, options: &Options)
     let n = options.n_with(h, w)?;
 
     let m2 = DMatrix::from_fn(h, w, |i, j| matrix[(i, j)].into());
+    let energy = m2.norm_squared();
+
+    let k = options.n_computed(n, h, w);
+    let (mut vectors, mut convergence) = truncated_svd(m2, k, 
+                                                       &options.svd_method, 
+                                                       options.eps.into(), 
+                                                       options.n_iter)?;
+    let n = n.min(options.n_for_error(&vectors, energy)?)
+             .min(options.n_for_size(&vectors, write_vectors_f64)?);
+    plot_if_asked(&singular_values(&vectors), n, energy, options)?;
+    vectors.truncate(n);
+    convergence.residuals.truncate(n);
+    report_convergence(&convergence, options);
+    report_energy(energy_of(&vectors), energy);
+    Ok((vectors, convergence))
+}
 
-    let svd = match m2.try_svd(true, true, options.eps.into(), 0) {
-        Some(x) => x,
-        None => return Err(Error::SVDError)
-        
-    };
+pub(crate) fn matrix_reduce_f32<T: Sample>(matrix: &DMatrix<T>, 
+                                           options: &Options)
+    -> Result<(SVDVectors<f32>, Convergence), Error>
+    {
+    
+    let (h, w) = matrix.shape();
+    let n = options.n_with(h, w)?;
 
-    let u = match svd.u {
-        Some(u) => u,
-        None => return Err(Error::NoSVDResult)
-    };
+    let m2 = DMatrix::from_fn(h, w, |i, j| matrix[(i, j)].to_f32());
+    let energy = m2.iter().map(|&x| (x as f64) * (x as f64)).sum();
+
+    let k = options.n_computed(n, h, w);
+    let (mut vectors, mut convergence) = truncated_svd(m2, k, 
+                                                       &options.svd_method, 
+                                                       options.eps, 
+                                                       options.n_iter)?;
+    let n = n.min(options.n_for_error(&vectors, energy)?)
+             .min(options.n_for_size(&vectors, write_vectors_f32)?);
+    plot_if_asked(&singular_values(&vectors), n, energy, options)?;
+    vectors.truncate(n);
+    convergence.residuals.truncate(n);
+    report_convergence(&convergence, options);
+    report_energy(energy_of(&vectors), energy);
+    Ok((vectors, convergence))
+}
 
-    let v_t = match svd.v_t {
-        Some(v_t) => v_t,
-        None => return Err(Error::NoSVDResult)
+/// Tiling of the planes, a single tile per plane if no tile size is given.
+fn tiling_of<T: Scalar>(planes: &[DMatrix<T>], options: &Options) -> Tiling {
+    let (h, w) = planes[0].shape();
+    let (tile_height, tile_width) = match options.tile_size {
+        Some(size) => (size, size),
+        None => (h, w)
     };
+    Tiling::new(h, w, tile_height, tile_width).with_planes(planes.len())
+}
 
-    let sv = svd.singular_values;
-
-    let mut res = Vec::with_capacity(n);
-    for i in 0..n {
-        let sv_i = sv[i];
-        let u_i = DVector::from_column_slice(u.column(i).as_slice());
-        let v_t_i= DVector::from_fn(w, |x, _| {  v_t[(i, x)]  });
-        res.push((sv_i, u_i, v_t_i));
-    }
+fn tiles_reduce_f64<T: Sample>(tiles: Vec<DMatrix<T>>, tiling: &Tiling, 
+                               options: &Options)
+    -> Result<(Vec<SVDVectors<f64>>, Convergence), Error>
+    {
 
-    Ok(res)
+    let tiles = tiles.into_iter()
+        .map(|t| t.map(|x| x.into()))
+        .collect();
+    let (tiles, convergence) = reduce_tiles(tiles, tiling, options, 
+                                            options.eps.into(), 
+                                            write_vectors_f64)?;
+    report_convergence(&convergence, options);
+    Ok((tiles, convergence))
 }
 
-fn matrix_reduce_f32(matrix: &DMatrix<i32>, options: &Options)
-    -> Result<SVDVectors<f32>, Error>
+fn tiles_reduce_f32<T: Sample>(tiles: Vec<DMatrix<T>>, tiling: &Tiling, 
+                               options: &Options)
+    -> Result<(Vec<SVDVectors<f32>>, Convergence), Error>
     {
-    
-    let (h, w) = matrix.shape();
-    let n = options.n_with(h, w)?;
 
-    let m2 = DMatrix::from_fn(h, w, |i, j| 
-        f32_from_i32_bad(matrix[(i, j)])
-    );
+    let tiles = tiles.into_iter()
+        .map(|t| t.map(|x| x.to_f32()))
+        .collect();
+    let (tiles, convergence) = reduce_tiles(tiles, tiling, options, 
+                                            options.eps, write_vectors_f32)?;
+    report_convergence(&convergence, options);
+    Ok((tiles, convergence))
+}
+
+/// Sum of the squared singular values of `vectors`.
+pub(crate) fn energy_of<N: RealField>(vectors: &SVDVectors<N>) -> f64 {
+    vectors.iter().map(|t| {
+        nalgebra::try_convert::<N, f64>(t.0).unwrap_or(0.0).powi(2)
+    }).sum()
+}
 
-    let svd = match m2.try_svd(true, true, options.eps, 0) {
-        Some(x) => x,
-        None => return Err(Error::SVDError)
-        
-    };
+pub(crate) fn singular_values<N: RealField>(vectors: &SVDVectors<N>) -> Vec<f64> {
+    vectors.iter().map(|t| {
+        nalgebra::try_convert::<N, f64>(t.0).unwrap_or(0.0)
+    }).collect()
+}
 
-    let u = match svd.u {
-        Some(u) => u,
-        None => return Err(Error::NoSVDResult)
-    };
+/// Draws the singular values `values` in the plot asked in `options`, if
+/// any, the first `cut` ones being kept.
+pub(crate) fn plot_if_asked(values: &[f64], cut: usize, energy: f64, 
+                            options: &Options) -> Result<(), Error> {
+    match &options.spectrum_plot {
+        Some(path) => plot_spectrum(values, cut, energy, path),
+        None => Ok(())
+    }
+}
 
-    let v_t = match svd.v_t {
-        Some(v_t) => v_t,
-        None => return Err(Error::NoSVDResult)
-    };
+/// Prints the fraction of `energy` kept by triplets whose squared singular 
+/// values sum to `kept`.
+pub(crate) fn report_energy(kept: f64, energy: f64) {
+    if energy > 0.0 {
+        println!("Energy kept: {:.3}%", 100.0 * kept / energy);
+    }
+}
 
-    let sv = svd.singular_values;
+/// Draws the error of each pixel in the heatmap asked in `options`, if any.
+pub(crate) fn heatmap_if_asked(original: &Samples, decoded: &Samples,
+                               options: &Options) -> Result<(), Error> {
+    match &options.error_heatmap {
+        Some(_) if options.is_wav => 
+            Err(Error::UnsupportedMode("error heatmap of a sound")),
+        Some(path) => error_heatmap(original, decoded, path),
+        None => Ok(())
+    }
+}
 
-    let mut res = Vec::with_capacity(n);
-    for i in 0..n {
-        let sv_i = sv[i];
-        let u_i = DVector::<f32>::from_column_slice(u.column(i).as_slice());
-        let v_t_i= DVector::<f32>::from_fn(w, |x, _| {  v_t[(i, x)]  });
-        res.push((sv_i, u_i, v_t_i));
+pub(crate) fn report_quality(quality: &Quality) {
+    println!("MSE: {:.3}, PSNR: {:.2} dB, max error: {}, SSIM: {:.4}",
+             quality.mse, quality.psnr, quality.max_error, quality.ssim);
+}
+
+/// `report_quality` of the approximation stored in a file with `header`, 
+/// that is not what a lossless file decodes to.
+pub(crate) fn report_stored_quality(quality: &Quality, header: &FileHeader) {
+    if header.lossless {
+        print!("Approximation (decoded with its difference to the input): ");
     }
+    report_quality(quality);
+}
 
-    Ok(res)
+pub(crate) fn report_convergence(convergence: &Convergence, options: &Options) {
+    if !convergence.converged {
+        println!("Warning: the SVD did not converge within {} iterations, \
+                  partially converged triplets are used (largest relative \
+                  residual: {:e}).", options.n_iter, 
+                  convergence.max_residual());
+    }
 }
 
 pub(crate) fn write_vectors_header<T>(fw: &mut FileWriter, vectors: &SVDVectors<T>,
-    options: &Options, header: Option<(WavHeader, u32)>) -> Result<(), Error> 
+    header: &FileHeader) -> Result<(), Error> 
     where T: std::fmt::Debug + nalgebra::Scalar
     {
     let n = vectors.len();
     let height = vectors[0].1.nrows();
     let width = vectors[0].2.nrows();
 
-    let file_type = if options.is_wav        { 0x8 } else { 0x0 } |
-                    if options.use_f64       { 0x4 } else { 0x0 } |
-                    if options.with_alpha    { 0x2 } else { 0x0 } |
-                    if options.use_aggregate { 0x1 } else { 0x0 };
-    fw.write_u8(file_type)?;
-
-    if options.is_wav {
-        let h = header.unwrap();
-        let x: [u8; 16] = h.0.into();
-        fw.write_all(&x)?;
-        fw.write_u32(h.1)?;
-    }
+    header.write(fw)?;
 
     fw.write_u32(n as u32)?;
     fw.write_u32(height as u32)?;
@@ -364,44 +1014,150 @@ pub(crate) fn write_vectors_header<T>(fw: &mut FileWriter, vectors: &SVDVectors<
     Ok(())
 }
 
-pub(crate) fn write_vectors_f64(fw: &mut FileWriter, vectors: &SVDVectors<f64>)
-    -> Result<(), Error> {
+/// The triplets as the decoder reads them back with `read` after they were
+/// written with `write`, that is after quantization.
+pub(crate) fn stored_vectors<N>(vectors: &SVDVectors<N>, header: &FileHeader,
+                                write: WriteVectors<N>, read: ReadVectors<N>)
+    -> Result<SVDVectors<N>, Error>
+    where N: Scalar
+    {
+    // floats are stored as they are
+    if header.quantization == 0 || vectors.is_empty() {
+        return Ok(vectors.clone());
+    }
+    let bytes = serialize(vectors, header, write)?;
+    read(&mut FileReader::from_bytes(bytes), header, vectors.len(), 
+         vectors[0].1.len(), vectors[0].2.len())
+}
 
-    let n = vectors.len();
-    let height = vectors[0].1.nrows();
-    let width = vectors[0].2.nrows();
+fn stored_tiles<N>(tiles: &[SVDVectors<N>], header: &FileHeader,
+                   write: WriteVectors<N>, read: ReadVectors<N>)
+    -> Result<Vec<SVDVectors<N>>, Error>
+    where N: Scalar
+    {
+    tiles.iter().map(|v| stored_vectors(v, header, write, read)).collect()
+}
+
+/// Bytes of `vectors` as written by `write`, before entropy coding.
+pub(crate) fn serialize<N>(vectors: &SVDVectors<N>, header: &FileHeader, 
+                           write: WriteVectors<N>) -> Result<Vec<u8>, Error> 
+    where N: Scalar
+    {
+    let mut mem = FileWriter::in_memory();
+    write(&mut mem, vectors, header)?;
+    Ok(mem.into_bytes())
+}
+
+/// Size of the stored triplets made of `bytes`, once compressed if the 
+/// header says so (see `FileHeader::overhead` for the compressed length).
+pub(crate) fn payload_size(bytes: &[u8], header: &FileHeader) -> usize {
+    if header.entropy_coded {
+        entropy::compress(bytes, header.symbol_period()).len()
+    } else {
+        bytes.len()
+    }
+}
+
+/// Largest `x` in `[lo, hi]` such that `fits(x)`, `fits` being decreasing 
+/// and `fits(lo)` assumed.
+pub(crate) fn largest_fitting<F>(lo: usize, hi: usize, mut fits: F) 
+    -> Result<usize, Error>
+    where F: FnMut(usize) -> Result<bool, Error>
+    {
+    let (mut lo, mut hi) = (lo, hi);
+    while lo < hi {
+        let mid = lo + (hi - lo + 1) / 2;
+        if fits(mid)? {  lo = mid;  }
+        else         {  hi = mid - 1;  }
+    }
+    Ok(lo)
+}
+
+/// Writes the body with `write`, compressing it first if the header says so.
+pub(crate) fn write_payload<F>(fw: &mut FileWriter, header: &FileHeader, write: F)
+    -> Result<(), Error>
+    where F: FnOnce(&mut FileWriter) -> Result<(), Error>
+    {
+    if header.entropy_coded {
+        let mut mem = FileWriter::in_memory();
+        write(&mut mem)?;
+        let compressed = entropy::compress(&mem.into_bytes(), 
+                                           header.symbol_period());
+        fw.write_u32(compressed.len() as u32)?;
+        fw.write_all(&compressed)?;
+        Ok(())
+    } else {
+        write(fw)
+    }
+}
+
+pub(crate) fn write_vectors_f64(fw: &mut FileWriter, vectors: &SVDVectors<f64>,
+    header: &FileHeader) -> Result<(), Error> {
+
+    let bits = header.quantization;
 
-    for i in 0..n {
-        let triplet = &vectors[i];
+    for triplet in vectors.iter() {
         fw.write_f64(triplet.0)?;
-        for j in 0..height 
-            { fw.write_f64(triplet.1[j])?; }
-        for j in 0..width 
-            { fw.write_f64(triplet.2[j])?; }
+        if bits == 0 {
+            for x in triplet.1.iter() 
+                { fw.write_f64(*x)?; }
+            for x in triplet.2.iter() 
+                { fw.write_f64(*x)?; }
+        } else {
+            for v in [&triplet.1, &triplet.2].iter() {
+                let scale = v.amax();
+                fw.write_f64(scale)?;
+                write_quantized(fw, v.iter().map(|&x| x / scale), bits)?;
+            }
+        }
     }
 
     Ok(())
 }
 
-pub(crate) fn write_vectors_f32(fw: &mut FileWriter, vectors: &SVDVectors<f32>)
-    -> Result<(), Error> {
+pub(crate) fn write_vectors_f32(fw: &mut FileWriter, vectors: &SVDVectors<f32>,
+    header: &FileHeader) -> Result<(), Error> {
 
-    let n = vectors.len();
-    let height = vectors[0].1.nrows();
-    let width = vectors[0].2.nrows();
+    let bits = header.quantization;
 
-    for i in 0..n {
-        let triplet = &vectors[i];
+    for triplet in vectors.iter() {
         fw.write_f32(triplet.0)?;
-        for j in 0..height 
-            { fw.write_f32(triplet.1[j])?; }
-        for j in 0..width 
-            { fw.write_f32(triplet.2[j])?; }
+        if bits == 0 {
+            for x in triplet.1.iter() 
+                { fw.write_f32(*x)?; }
+            for x in triplet.2.iter() 
+                { fw.write_f32(*x)?; }
+        } else {
+            for v in [&triplet.1, &triplet.2].iter() {
+                let scale = v.amax();
+                fw.write_f32(scale)?;
+                write_quantized(fw, v.iter().map(|&x| (x / scale) as f64), bits)?;
+            }
+        }
     }
 
     Ok(())
 }
 
+/// Writes `values`, that have to lie in `[-1, 1]`, as signed integers of 
+/// `bits` bits, the last byte being padded with zeros.
+pub(crate) fn write_quantized<I>(fw: &mut FileWriter, values: I, bits: u8)
+    -> Result<(), Error>
+    where I: Iterator<Item = f64>
+    {
+    let q_max = ((1_i32 << (bits - 1)) - 1) as f64;
+
+    for x in values {
+        // NaN (all zero vector) is mapped to 0
+        let q = if x.is_nan() { 0 } 
+                else { (x * q_max).round().max(-q_max).min(q_max) as i32 };
+        fw.write_bits(q as u32, bits)?;
+    }
+    fw.flush_bits()?;
+
+    Ok(())
+}
+
 impl CompressionPolicy {
     pub fn with_number(n: usize) -> Self {
         CompressionPolicy::Number(n)
@@ -409,6 +1165,28 @@ impl CompressionPolicy {
     pub fn with_ratio_percentage(r: u8) -> Self {
         CompressionPolicy::Ratio(r)
     }
+    pub fn with_psnr(db: f64) -> Self {
+        CompressionPolicy::Quality(QualityTarget::Psnr(db))
+    }
+    pub fn with_relative_error(e: f64) -> Self {
+        CompressionPolicy::Quality(QualityTarget::RelativeError(e))
+    }
+    pub fn with_energy_percentage(p: f64) -> Self {
+        CompressionPolicy::Energy(p / 100_f64)
+    }
+    pub fn with_max_size(bytes: u64) -> Self {
+        CompressionPolicy::Size(bytes)
+    }
+
+    /// Whether the number of triplets is chosen from their singular values
+    /// rather than from the size of the file.
+    pub fn targets_error(&self) -> bool {
+        match self {
+            CompressionPolicy::Quality(_) | CompressionPolicy::Energy(_) => 
+                true,
+            _ => false
+        }
+    }
 }
 
 impl Options {
@@ -418,11 +1196,23 @@ impl Options {
             policy: CompressionPolicy::with_ratio_percentage(25),
             use_f64: true,
             n_iter: 0,
+            svd_method: SVDMethod::Exact,
             original_file_size: 0,
 
-            use_aggregate: true,
             aggregator: Some(Box::new(aggregate::Aggregator1)),
             with_alpha: false,
+            quantization: None,
+            entropy_coding: false,
+            tile_size: None,
+            planar: false,
+            colour_space: ColourSpace::Rgb,
+            lossless: false,
+            tolerance: None,
+            layout: Layout::Square,
+            segment_length: None,
+            rank: None,
+            spectrum_plot: None,
+            error_heatmap: None,
 
             is_wav: false,
             bits_per_sample: None,
@@ -430,6 +1220,16 @@ impl Options {
         }
     }
 
+    /// Number of triplets of a `h x w` matrix to compute to keep `n` of
+    /// them: all of them if their spectrum is plotted and the full
+    /// decomposition is computed anyway.
+    pub (crate) fn n_computed(&self, n: usize, h: usize, w: usize) -> usize {
+        match (&self.spectrum_plot, &self.svd_method) {
+            (Some(_), SVDMethod::Exact) => h.min(w),
+            _ => n
+        }
+    }
+
     pub (crate) fn n_with(&self, h: usize, w: usize) -> Result<usize, Error> {
         println!("Original file size: {}", self.original_file_size);
 
@@ -439,19 +1239,127 @@ impl Options {
                 Ok(n)
             },
             CompressionPolicy::Ratio(r) => {
-                let data_bits = if self.use_f64 {  8_f64  }
-                                else            {  4_f64  };
                 let r_f64 = r as f64 / 100_f64;
-
-                let vector_size = data_bits * (1_f64 + h as f64 + w as f64);
+                let vector_size = self.triplet_cost(h, w);
                 // n * vector_size = r_f64 * img_size
                 let n = (r_f64 * self.original_file_size as f64) / vector_size;
                 if n.round() <= 0.0 {  return Err(Error::RatioTooRestrictive);  }
                 println!("Output file size: {}", n * vector_size);
                 Ok(n.round() as usize)
+            },
+            // every triplet is needed to choose
+            CompressionPolicy::Quality(_) | CompressionPolicy::Energy(_) => 
+                Ok(h.min(w)),
+            CompressionPolicy::Size(max) => {
+                let header = FileHeader::from_options(self, None);
+                // the size of compressed triplets is only known once 
+                // computed, see `n_for_size`
+                if header.entropy_coded {  return Ok(h.min(w));  }
+
+                let available = (max as usize)
+                    .saturating_sub(header.overhead(None));
+                let n = available / header.triplet_size(h, w);
+                if n == 0 {  return Err(Error::RatioTooRestrictive);  }
+                Ok(n.min(h.min(w)))
             }
         }
     }
+
+    /// Number of the (sorted) triplets to keep so that the file holding them
+    /// with `write` fits the size asked by the policy: all of them if the 
+    /// policy does not ask a size or if the body is not compressed (see 
+    /// `n_with`), the most fitting (at least one) otherwise.
+    pub(crate) fn n_for_size<N>(&self, vectors: &SVDVectors<N>, 
+                                write: WriteVectors<N>) -> Result<usize, Error>
+        where N: RealField
+        {
+        let header = FileHeader::from_options(self, None);
+        let max = match self.policy {
+            CompressionPolicy::Size(max) if header.entropy_coded => 
+                max as usize,
+            _ => return Ok(vectors.len())
+        };
+        if vectors.is_empty() {  return Ok(0);  }
+
+        let bytes = serialize(vectors, &header, write)?;
+        let triplet_size = bytes.len() / vectors.len();
+        let size = |n: usize| header.overhead(None) + 
+                              payload_size(&bytes[..n * triplet_size], &header);
+        if size(1) > max {
+            return Err(Error::RatioTooRestrictive);
+        }
+        largest_fitting(1, vectors.len(), |n| Ok(size(n) <= max))
+    }
+
+    /// Largest value of the samples, used to compute the PSNR: 255 for 
+    /// the channels of an image, `2^bits - 1` for sounds (1 for float 
+    /// samples).
+    pub(crate) fn peak(&self) -> f64 {
+        if self.is_wav && self.bits_per_sample == Some(32) {
+            return 1.0;
+        }
+        let bits = if self.is_wav {
+            self.bits_per_sample.unwrap_or(16) as i32
+        } else { 8 };
+        2_f64.powi(bits) - 1.0
+    }
+
+    /// Largest squared error (summed over the `n_values` values of the 
+    /// matrix, whose squared norm is `energy`) allowed by the policy, 0 if 
+    /// the policy does not target a quality. A PSNR cannot be targeted when
+    /// the pixels are aggregated, as the error of the aggregated values does
+    /// not tell the one of the pixels.
+    pub(crate) fn allowed_error(&self, energy: f64, n_values: usize) 
+        -> Result<f64, Error> {
+        match self.policy {
+            CompressionPolicy::Quality(QualityTarget::Psnr(_)) 
+                if !self.is_wav && self.aggregator.is_some() && 
+                   !self.planar =>
+                Err(Error::UnsupportedMode("PSNR of aggregated pixels")),
+            CompressionPolicy::Quality(QualityTarget::Psnr(db)) => {
+                let mse = self.peak() * self.peak() / 10_f64.powf(db / 10.0);
+                Ok(mse * n_values as f64)
+            },
+            CompressionPolicy::Quality(QualityTarget::RelativeError(e)) =>
+                Ok(e * e * energy),
+            CompressionPolicy::Energy(f) => Ok((1.0 - f) * energy),
+            _ => Ok(0.0)
+        }
+    }
+
+    /// Number of the (sorted) triplets of a matrix whose squared norm is 
+    /// `energy` to keep: all of them, unless the policy targets a quality or 
+    /// an energy, in which case the fewest reaching it (but at least one).
+    pub(crate) fn n_for_error<N>(&self, vectors: &SVDVectors<N>, energy: f64)
+        -> Result<usize, Error>
+        where N: RealField
+        {
+        if !self.policy.targets_error() {
+            return Ok(vectors.len());
+        }
+
+        let n_values = match vectors.first() {
+            Some(t) => t.1.len() * t.2.len(),
+            None => return Ok(0)
+        };
+        let allowed = self.allowed_error(energy, n_values)?;
+
+        let mut error = energy;
+        for (i, triplet) in vectors.iter().enumerate() {
+            if i > 0 && error <= allowed {
+                return Ok(i);
+            }
+            let sv = nalgebra::try_convert::<N, f64>(triplet.0).unwrap_or(0.0);
+            error -= sv * sv;
+        }
+        Ok(vectors.len())
+    }
+
+    /// Size in bytes of one stored triplet of a `h x w` matrix, before 
+    /// entropy coding.
+    pub(crate) fn triplet_cost(&self, h: usize, w: usize) -> f64 {
+        FileHeader::from_options(self, None).triplet_size(h, w) as f64
+    }
 }
 
 impl<T> Printable for SVDVectors<T> 
@@ -482,4 +1390,331 @@ fn f32_from_i32_bad(x: i32) -> f32 {
     }
 
     if x < 0 { -r } else { r }
-}
\ No newline at end of file
+}
+
+#[allow(dead_code, unused_imports)]
+mod tests {
+    use super::{encode, refine, matrix_reduce_f64, Options, 
+                CompressionPolicy};
+    use crate::{
+        Error,
+        read::FileReader,
+        header::FileHeader,
+        decode::{decode, read_file_dimensions},
+        tile::read_tiles_header,
+        metrics::compare,
+    };
+    use image::{Rgb, RgbImage};
+    use nalgebra::{DMatrix, DVector};
+    use wav::{BitDepth as WavData, Header as WavHeader};
+
+    /// Path of a file named `name` in the temporary directory.
+    fn temp(name: &str) -> String {
+        std::env::temp_dir().join(name).to_str().unwrap().to_string()
+    }
+
+    /// Writes a smooth image of `width x height` pixels in `name`.
+    fn test_image(name: &str, width: u32, height: u32) -> String {
+        let path = temp(name);
+        RgbImage::from_fn(width, height, |i, j| {
+            let (x, y) = (i as f64, j as f64);
+            let value = |c: f64| {
+                (128.0 + 60.0 * (x / (5.0 + c)).sin() * (y / 7.0).cos() 
+                       + 30.0 * (x * y / (40.0 + 10.0 * c)).sin()) as u8
+            };
+            Rgb([value(0.0), value(1.0), value(2.0)])
+        }).save(&path).unwrap();
+        path
+    }
+
+    /// Writes a mono 16-bit sound of `n` samples in `name`.
+    fn test_sound(name: &str, n: usize) -> String {
+        let path = temp(name);
+        let samples = (0..n).map(|k| {
+            let t = k as f64 / 8000.0;
+            (8000.0 * (440.0 * 6.283 * t).sin() 
+             + 3000.0 * (1250.0 * 6.283 * t).sin()) as i16
+        }).collect();
+        let mut file = std::fs::File::create(&path).unwrap();
+        wav::write(WavHeader::new(1, 1, 8000, 16), WavData::Sixteen(samples), 
+                   &mut file).unwrap();
+        path
+    }
+
+    /// Header of the compressed file `bytes`, the dimensions and number of 
+    /// triplets of each of its tiles (a single one if it is not tiled), and
+    /// where the triplets start.
+    fn stored_tiles(bytes: &[u8]) 
+        -> (FileHeader, Vec<(usize, usize)>, Vec<usize>, usize) {
+        let mut fr = FileReader::from_bytes(bytes.to_vec());
+        let header = FileHeader::read(&mut fr).unwrap();
+        if header.tiled {
+            let (tiling, ranks) = read_tiles_header(&mut fr, &header).unwrap();
+            let start = header.size() + 16 + 4 * tiling.n_blocks();
+            (header, tiling.blocks(), ranks, start)
+        } else {
+            let (n, h, w) = read_file_dimensions(&mut fr, &header).unwrap();
+            let start = header.size() + 12;
+            (header, vec![(h, w)], vec![n], start)
+        }
+    }
+
+    /// Bytes of the triplets of every tile of the compressed file `bytes`, a
+    /// single tile if it is not tiled.
+    fn tile_bodies(bytes: Vec<u8>) -> Vec<Vec<u8>> {
+        let (header, blocks, ranks, mut start) = stored_tiles(&bytes);
+        let mut bodies = Vec::new();
+        for (&(h, w), n) in blocks.iter().zip(ranks) {
+            let size = n * header.triplet_size(h, w);
+            bodies.push(bytes[start..start + size].to_vec());
+            start += size;
+        }
+        bodies
+    }
+
+    #[test]
+    fn test_max_size() {
+        let image = test_image("svd-img-size.png", 24, 20);
+        let sound = test_sound("svd-img-size.wav", 4000);
+        let output = temp("svd-img-size.svd");
+        let max = 3000;
+        let cases: Vec<(&String, fn(&mut Options))> = vec![
+            (&image, |_| {}),
+            (&image, |o| o.quantization = Some(8)),
+            (&image, |o| { 
+                o.quantization = Some(8); 
+                o.entropy_coding = true; 
+            }),
+            (&image, |o| o.tile_size = Some(16)),
+            (&sound, |_| {}),
+        ];
+        let size = |options: &mut Options, input: &str| {
+            encode(input, &output, options).unwrap();
+            std::fs::metadata(&output).unwrap().len()
+        };
+        for (input, set) in cases.iter() {
+            let mut options = Options::default();
+            options.aggregator = None;
+            set(&mut options);
+            options.policy = CompressionPolicy::with_max_size(max);
+            assert!(size(&mut options, input) <= max);
+
+            // one more triplet would not fit
+            let (header, blocks, ranks, _) = 
+                stored_tiles(&std::fs::read(&output).unwrap());
+            assert!(ranks.iter().sum::<usize>() > 0);
+            if header.tiled {
+                let larger = blocks.iter().zip(ranks.iter())
+                    .filter(|(&(h, w), &n)| n < h.min(w))
+                    .map(|(&(h, w), _)| header.triplet_size(h, w) as u64)
+                    .min().unwrap();
+                let size = std::fs::metadata(&output).unwrap().len();
+                assert!(size + larger > max);
+            } else {
+                let mut options = Options::default();
+                options.aggregator = None;
+                set(&mut options);
+                options.policy = CompressionPolicy::with_number(ranks[0] + 1);
+                assert!(size(&mut options, input) > max);
+            }
+        }
+        for file in [image, sound, output].iter() {
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_float_sound() {
+        let (sound, output, decoded) = (temp("svd-img-float.wav"), 
+                                        temp("svd-img-float.svd"),
+                                        temp("svd-img-float-decoded.wav"));
+        let samples: Vec<f32> = (0..4096).map(|k| {
+            let t = k as f32 / 8000.0;
+            0.6 * (440.0 * 6.283 * t).sin() + 0.2 * (1250.0 * 6.283 * t).cos()
+        }).collect();
+        wav::write(WavHeader::new(3, 1, 8000, 32), 
+                   WavData::ThirtyTwoFloat(samples.clone()),
+                   &mut std::fs::File::create(&sound).unwrap()).unwrap();
+
+        for &(n, bound) in [(64, 1e-5), (8, 1e-3)].iter() {
+            let mut options = Options::default();
+            options.policy = CompressionPolicy::with_number(n);
+            encode(&sound, &output, &mut options).unwrap();
+            decode(&output, &decoded, &mut Options::default()).unwrap();
+
+            let (header, data) = wav::read(&mut std::fs::File::open(&decoded)
+                                                   .unwrap()).unwrap();
+            assert_eq!((header.audio_format, header.bits_per_sample), (3, 32));
+            assert_eq!(header.sampling_rate, 8000);
+            let result = match data {
+                WavData::ThirtyTwoFloat(x) => x,
+                _ => panic!("wrong bit depth"),
+            };
+            assert_eq!(result.len(), samples.len());
+            let error = samples.iter().zip(result.iter())
+                               .map(|(a, b)| (a - b).abs())
+                               .fold(0.0, f32::max);
+            assert!(error <= bound);
+        }
+        for file in [sound, output, decoded].iter() {
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_energy() {
+        // a matrix whose singular values are `sv`
+        let sv = [10.0, 6.0, 4.0, 2.0, 1.0, 0.5];
+        let orthogonal = |a: usize| DMatrix::from_fn(6, 6, |i, j| {
+            ((i * a + j * 3) % 5) as f64 + if i == j { 3.0 } else { 0.0 }
+        }).qr().q();
+        let diagonal = DMatrix::from_diagonal(&DVector::from_row_slice(&sv));
+        let m = orthogonal(7) * diagonal * orthogonal(2).transpose();
+
+        let total: f64 = sv.iter().map(|x| x * x).sum();
+        let mut kept = 0.0;
+        for (rank, x) in sv.iter().enumerate() {
+            // fractions only reached with the triplet of index `rank`
+            let missing = x * x;
+            for &part in [0.01, 0.5, 1.0].iter() {
+                let fraction = (kept + part * missing) / total;
+                let mut options = Options::default();
+                options.policy = CompressionPolicy::Energy(fraction - 1e-12);
+                let (vectors, _) = matrix_reduce_f64(&m, &options).unwrap();
+                assert_eq!(vectors.len(), rank + 1);
+            }
+            kept += missing;
+        }
+    }
+
+    #[test]
+    fn test_quality_target() {
+        let sound = test_sound("svd-img-target.wav", 4096);
+        let output = temp("svd-img-target.svd");
+        let decoded = temp("svd-img-target-decoded.wav");
+        let samples = match wav::read(&mut std::fs::File::open(&sound)
+                                                      .unwrap()).unwrap().1 {
+            WavData::Sixteen(x) => x,
+            _ => panic!("wrong bit depth"),
+        };
+        let energy: f64 = samples.iter().map(|&x| (x as f64).powi(2)).sum();
+        let relative_error = |mse: f64| (mse * samples.len() as f64 / energy)
+                                        .sqrt();
+        // number of triplets kept with `policy` and quality of the result
+        let encoded = |policy: CompressionPolicy| {
+            let mut options = Options::default();
+            options.policy = policy;
+            encode(&sound, &output, &mut options).unwrap();
+            let (_, _, ranks, _) = 
+                stored_tiles(&std::fs::read(&output).unwrap());
+            decode(&output, &decoded, &mut Options::default()).unwrap();
+            (ranks[0], compare(&sound, &decoded, 
+                               &mut Options::default()).unwrap())
+        };
+
+        let (rank, quality) = encoded(CompressionPolicy::with_psnr(70.0));
+        assert!(rank > 1);
+        assert!(quality.psnr >= 70.0);
+        let (_, quality) = encoded(CompressionPolicy::with_number(rank - 1));
+        assert!(quality.psnr < 70.0);
+
+        let (rank, quality) = 
+            encoded(CompressionPolicy::with_relative_error(0.01));
+        assert!(rank > 1);
+        assert!(relative_error(quality.mse) <= 0.01);
+        let (_, quality) = encoded(CompressionPolicy::with_number(rank - 1));
+        assert!(relative_error(quality.mse) > 0.01);
+
+        // the error of aggregated values does not tell the one of the pixels
+        let image = test_image("svd-img-target.png", 24, 20);
+        let mut options = Options::default();
+        options.policy = CompressionPolicy::with_psnr(30.0);
+        match encode(&image, &output, &mut options) {
+            Err(e) => assert_eq!(format!("{:?}", e), format!("{:?}", 
+                Error::UnsupportedMode("PSNR of aggregated pixels"))),
+            Ok(_) => panic!("PSNR of aggregated pixels targeted"),
+        }
+        for file in [sound, image, output, decoded].iter() {
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let original = test_image("svd-img-summary.png", 24, 20);
+        let output = temp("svd-img-summary.svd");
+        let decoded = temp("svd-img-summary-decoded.png");
+        let mut qualities = Vec::new();
+        for &(lossless, tolerance) in [(false, None), (true, None), 
+                                       (false, Some(4))].iter() {
+            let mut options = Options::default();
+            options.aggregator = None;
+            options.policy = CompressionPolicy::with_number(3);
+            options.lossless = lossless;
+            options.tolerance = tolerance;
+            let summary = encode(&original, &output, &mut options).unwrap();
+            assert!(summary.convergence.converged);
+            decode(&output, &decoded, &mut Options::default()).unwrap();
+            let quality = compare(&original, &decoded, 
+                                  &mut Options::default()).unwrap();
+            qualities.push((summary.quality, quality));
+        }
+
+        // the quality of the decoded file...
+        let (summary, decoded_quality) = &qualities[0];
+        assert!((summary.mse - decoded_quality.mse).abs() < 1e-9);
+        assert_eq!(summary.max_error, decoded_quality.max_error);
+        // ...of the approximation of a lossless one, that decodes exactly...
+        let (lossless, decoded_lossless) = &qualities[1];
+        assert_eq!(lossless.mse, summary.mse);
+        assert!(lossless.mse > 0.0);
+        assert_eq!(decoded_lossless.mse, 0.0);
+        // ...and of a near-lossless one once corrected
+        let (corrected, decoded_corrected) = &qualities[2];
+        assert!(corrected.max_error <= 4.0);
+        assert!((corrected.mse - decoded_corrected.mse).abs() < 1e-9);
+        for file in [original, output, decoded].iter() {
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_refine() {
+        let original = test_image("svd-img-refine.png", 24, 20);
+        let (stored, refined) = (temp("svd-img-refine.svd"), 
+                                 temp("svd-img-refined.svd"));
+        let decoded = temp("svd-img-refine-decoded.png");
+        for &tile_size in [None, Some(16)].iter() {
+            let mut options = Options::default();
+            options.aggregator = None;
+            options.tile_size = tile_size;
+            options.policy = CompressionPolicy::with_number(2);
+            encode(&original, &stored, &mut options).unwrap();
+            let mut options = Options::default();
+            options.policy = CompressionPolicy::with_number(6);
+            refine(&stored, &refined, &original, &mut options).unwrap();
+
+            // the stored triplets of every tile are copied as they are
+            let old = tile_bodies(std::fs::read(&stored).unwrap());
+            let new = tile_bodies(std::fs::read(&refined).unwrap());
+            assert_eq!(old.len(), new.len());
+            for (o, n) in old.iter().zip(new.iter()) {
+                assert!(n.starts_with(o));
+            }
+            let added: usize = new.iter().zip(old.iter())
+                                  .map(|(n, o)| n.len() - o.len()).sum();
+            assert!(added > 0);
+
+            let mut errors = Vec::new();
+            for file in [&stored, &refined].iter() {
+                decode(file, &decoded, &mut Options::default()).unwrap();
+                errors.push(compare(&original, &decoded, 
+                                    &mut Options::default()).unwrap().mse);
+            }
+            assert!(errors[1] < errors[0]);
+        }
+        for file in [original, stored, refined, decoded].iter() {
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Adaptive binary range coder (the one of LZMA), used to compress the body
+//! of the files.
+//!
+//! Every byte is coded bit by bit with a binary tree of probabilities. The
+//! tree used depends on the position of the byte modulo a `period`, so that
+//! e.g. the exponent bytes of floats do not share statistics with the bytes
+//! of their fractional part.
+
+use super::Error;
+
+const PROB_BITS: u32 = 11;
+const PROB_INIT: u16 = 1 << (PROB_BITS - 1);
+const MOVE_BITS: u32 = 5;
+const TOP: u32 = 1 << 24;
+
+/// Upper bound of the ratio between decompressed and compressed sizes: the
+/// most probable bit costs at least 1/45 bit, so a byte at least 1/6 bit.
+pub const MAX_RATIO: usize = 64;
+
+struct Encoder {
+    low: u64,
+    range: u32,
+    cache: u8,
+    cache_size: u64,
+    out: Vec<u8>,
+}
+
+impl Encoder {
+    fn new() -> Self {
+        Encoder {
+            low: 0,
+            range: 0xffff_ffff,
+            cache: 0,
+            cache_size: 1,
+            out: Vec::new(),
+        }
+    }
+
+    fn encode_bit(&mut self, prob: &mut u16, bit: u32) {
+        let bound = (self.range >> PROB_BITS) * (*prob as u32);
+        if bit == 0 {
+            self.range = bound;
+            *prob += ((1 << PROB_BITS) - *prob) >> MOVE_BITS;
+        } else {
+            self.low += bound as u64;
+            self.range -= bound;
+            *prob -= *prob >> MOVE_BITS;
+        }
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    fn shift_low(&mut self) {
+        if (self.low as u32) < 0xff00_0000 || (self.low >> 32) != 0 {
+            let carry = (self.low >> 32) as u8;
+            let mut x = self.cache;
+            loop {
+                self.out.push(x.wrapping_add(carry));
+                x = 0xff;
+                self.cache_size -= 1;
+                if self.cache_size == 0 {  break;  }
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00ff_ffff) << 8;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for _i in 0..5 {
+            self.shift_low();
+        }
+        self.out
+    }
+}
+
+struct Decoder<'a> {
+    code: u32,
+    range: u32,
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        let mut d = Decoder {
+            code: 0,
+            range: 0xffff_ffff,
+            data,
+            pos: 0,
+        };
+        for _i in 0..5 {
+            d.code = (d.code << 8) | d.next_byte() as u32;
+        }
+        d
+    }
+
+    /// Bytes after the end of the data are read as zeros: a corrupted
+    /// stream gives garbage, not a crash.
+    fn next_byte(&mut self) -> u8 {
+        let x = if self.pos < self.data.len() { self.data[self.pos] }
+                else { 0 };
+        self.pos += 1;
+        x
+    }
+
+    fn decode_bit(&mut self, prob: &mut u16) -> u32 {
+        let bound = (self.range >> PROB_BITS) * (*prob as u32);
+        let bit = if self.code < bound {
+            self.range = bound;
+            *prob += ((1 << PROB_BITS) - *prob) >> MOVE_BITS;
+            0
+        } else {
+            self.code -= bound;
+            self.range -= bound;
+            *prob -= *prob >> MOVE_BITS;
+            1
+        };
+        while self.range < TOP {
+            self.range <<= 8;
+            self.code = (self.code << 8) | self.next_byte() as u32;
+        }
+        bit
+    }
+}
+
+/// Compresses `data`, using one model for each position modulo `period`.
+pub fn compress(data: &[u8], period: usize) -> Vec<u8> {
+    let mut probs = vec![[PROB_INIT; 256]; period];
+    let mut enc = Encoder::new();
+
+    for (k, &b) in data.iter().enumerate() {
+        let tree = &mut probs[k % period];
+        let mut m = 1_usize;
+        for i in (0..8).rev() {
+            let bit = (b as u32 >> i) & 1;
+            enc.encode_bit(&mut tree[m], bit);
+            m = (m << 1) | bit as usize;
+        }
+    }
+
+    enc.finish()
+}
+
+/// Decompresses `size` bytes compressed by `compress` with the same
+/// `period`.
+pub fn decompress(data: &[u8], size: usize, period: usize)
+    -> Result<Vec<u8>, Error> {
+
+    if size > MAX_RATIO * data.len() {
+        return Err(Error::InvalidHeader("inconsistent compressed size"));
+    }
+
+    let mut probs = vec![[PROB_INIT; 256]; period];
+    let mut dec = Decoder::new(data);
+    let mut res = Vec::with_capacity(size);
+
+    for k in 0..size {
+        let tree = &mut probs[k % period];
+        let mut m = 1_usize;
+        for _i in 0..8 {
+            let bit = dec.decode_bit(&mut tree[m]);
+            m = (m << 1) | bit as usize;
+        }
+        res.push(m as u8);
+    }
+
+    if dec.pos > data.len() {
+        return Err(Error::InvalidHeader("truncated compressed data"));
+    }
+
+    Ok(res)
+}
+
+#[allow(dead_code, unused_imports)]
+mod tests {
+    use super::{compress, decompress};
+
+    fn round_trip(data: &[u8], period: usize) -> usize {
+        let c = compress(data, period);
+        let d = decompress(&c, data.len(), period).unwrap();
+        assert_eq!(&d[..], data);
+        c.len()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(&[], 1);
+        round_trip(&[0xff], 3);
+        let noisy: Vec<u8> = (0..5000_u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        round_trip(&noisy, 8);
+    }
+
+    #[test]
+    fn test_compresses() {
+        let data: Vec<u8> = (0..10000_u32)
+            .map(|i| if i % 4 == 0 { (i % 7) as u8 } else { 0 })
+            .collect();
+        assert!(round_trip(&data, 4) < data.len() / 5);
+        let zeros = vec![0_u8; 100000];
+        assert!(round_trip(&zeros, 1) * super::MAX_RATIO > zeros.len());
+    }
+}
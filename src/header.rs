@@ -0,0 +1,615 @@
+use super::{
+    Error,
+    read::FileReader,
+    write::FileWriter,
+    encode::Options,
+    aggregate,
+    colour::{self, ColourSpace},
+    layout::{self, Layout},
+};
+
+use wav::Header as WavHeader;
+
+/// Signature written at the very beginning of every compressed file.
+pub const MAGIC: [u8; 4] = *b"SVDc";
+
+/// Version of the container format written by this program. Files written
+/// before the magic signature existed are read as version 0.
+pub const FORMAT_VERSION: u8 = 11;
+
+/// Bit depths that can be used to quantize the singular vectors.
+pub const QUANTIZATION_BITS: [u8; 3] = [8, 12, 16];
+
+/// Size of the WAV header and sample count stored in sound files.
+const WAV_HEADER_SIZE: usize = 16 + 4;
+
+/// Audio format of the WAV files with float samples.
+const WAV_FORMAT_FLOAT: u16 = 3;
+
+/// Largest number of rows or columns accepted when reading a file.
+pub const MAX_DIMENSION: usize = 1 << 20;
+
+const FLAG_LOSSLESS:  u8 = 0x80;
+const FLAG_PLANAR:    u8 = 0x40;
+const FLAG_TILED:     u8 = 0x20;
+const FLAG_ENTROPY:   u8 = 0x10;
+const FLAG_SOUND:     u8 = 0x8;
+const FLAG_F64:       u8 = 0x4;
+const FLAG_ALPHA:     u8 = 0x2;
+const FLAG_AGGREGATE: u8 = 0x1;
+/// Bits of the `extensions` byte, that follows the colour space since
+/// version 9.
+const EXT_TOLERANCE:  u8 = 0x1;
+const EXT_LAYOUT:     u8 = 0x2;
+const EXT_SEGMENTS:   u8 = 0x4;
+const KNOWN_EXTENSIONS: u8 = EXT_TOLERANCE | EXT_LAYOUT | EXT_SEGMENTS;
+/// Flags of version 0 files, the other bits are used to recognize them.
+const LEGACY_FLAGS:   u8 = 0xf;
+
+/// Flags that can be set in a file of the given version.
+fn known_flags(version: u8) -> u8 {
+    match version {
+        0..=3 => LEGACY_FLAGS,
+        4 => 0x1f,
+        5 => 0x3f,
+        6 | 7 => 0x7f,
+        _ => 0xff,
+    }
+}
+
+/// Everything stored in a compressed file before the body.
+pub struct FileHeader {
+    pub version: u8,
+    pub is_sound: bool,
+    pub use_f64: bool,
+    pub with_alpha: bool,
+    /// Id of the aggregator used to build the matrix, see `aggregate::from_id`.
+    pub aggregator: u8,
+    /// Number of bits of the quantized singular vectors, 0 if they are
+    /// stored as floats.
+    pub quantization: u8,
+    /// Whether the body is compressed with `entropy::compress`.
+    pub entropy_coded: bool,
+    /// Whether the matrix is split in tiles, see `tile::Tiling`.
+    pub tiled: bool,
+    /// Whether every channel of the image (or of the sound, if it has more
+    /// than one) is decomposed separately. The body is then always tiled,
+    /// with one tile per plane if the planes are not split.
+    pub planar: bool,
+    /// Id of the colour space of the planes (or of the space of the channels
+    /// of a sound), see `ColourSpace::from_id`.
+    pub colour_space: u8,
+    /// Whether the difference between the input and the matrices recomputed
+    /// from the triplets follows the body, see `residual`.
+    pub lossless: bool,
+    /// Largest difference between a decoded sample and the input one,
+    /// guaranteed by the correction following the body (near-lossless file),
+    /// see `correction`.
+    pub tolerance: Option<u32>,
+    /// Id of the layout of the samples of each channel of a sound in its
+    /// matrix, see `Layout::from_id`.
+    pub layout: u8,
+    /// Length of the frames (or window) of the layout, 0 if it has none.
+    pub frame_length: u32,
+    /// Number of frames of the segments of a sound, each one stored after
+    /// the previous one with its own tiles and triplets (see `segment`), if
+    /// it is split.
+    pub segment_length: Option<u32>,
+    pub wav: Option<(WavHeader, u32)>,
+}
+
+impl FileHeader {
+    pub fn from_options(options: &Options, wav: Option<(WavHeader, u32)>)
+        -> Self {
+        let planar = options.planar;
+        let segment_length = options.segment_length.filter(|_| options.is_wav)
+                                                   .map(|l| l as u32);
+        FileHeader {
+            version: FORMAT_VERSION,
+            is_sound: options.is_wav,
+            use_f64: options.use_f64,
+            with_alpha: options.with_alpha,
+            aggregator: if options.is_wav { aggregate::NO_AGGREGATOR }
+                        else { aggregate::id_of(&options.aggregator) },
+            quantization: options.quantization.unwrap_or(0),
+            entropy_coded: options.entropy_coding,
+            tiled: options.tile_size.is_some() || planar || 
+                   segment_length.is_some(),
+            planar,
+            colour_space: if planar { options.colour_space.id() } 
+                          else { colour::RGB },
+            lossless: options.lossless,
+            tolerance: options.tolerance,
+            layout: options.layout.id(),
+            frame_length: options.layout.length(),
+            segment_length,
+            wav: if options.is_wav { wav } else { None },
+        }
+    }
+
+    fn flags(&self) -> u8 {
+        let flags = if self.lossless   { FLAG_LOSSLESS  } else { 0x0 } |
+                    if self.planar     { FLAG_PLANAR    } else { 0x0 } |
+                    if self.tiled      { FLAG_TILED     } else { 0x0 } |
+                    if self.entropy_coded { FLAG_ENTROPY } else { 0x0 } |
+                    if self.is_sound   { FLAG_SOUND     } else { 0x0 } |
+                    if self.use_f64    { FLAG_F64       } else { 0x0 } |
+                    if self.with_alpha { FLAG_ALPHA     } else { 0x0 } |
+                    if self.aggregate() { FLAG_AGGREGATE } else { 0x0 };
+        flags
+    }
+
+    /// Whether the samples of the sound are 32-bit floats.
+    pub fn float_samples(&self) -> bool {
+        match self.wav {
+            Some((h, _)) => h.audio_format == WAV_FORMAT_FLOAT,
+            None => false
+        }
+    }
+
+    /// Layout of the samples of the sound, `Square` if it is invalid (see
+    /// `validate`).
+    pub fn layout(&self) -> Layout {
+        Layout::from_id(self.layout, self.frame_length).unwrap_or(Layout::Square)
+    }
+
+    /// Whether the matrices hold 24-bit samples the way `wav` keeps them, in
+    /// the 3 upper bytes of an `i32`, rather than their value (sounds written
+    /// before version 10).
+    pub fn shifted_samples(&self) -> bool {
+        match self.wav {
+            Some((h, _)) => self.version < 10 && h.bits_per_sample == 24,
+            None => false
+        }
+    }
+
+    /// Whether a correction only holds the corrected samples rather than 
+    /// every one of them (near-lossless files written since version 11).
+    pub fn sparse_correction(&self) -> bool {
+        self.version >= 11
+    }
+
+    /// Whether the pixels were aggregated into one value.
+    pub fn aggregate(&self) -> bool {
+        self.aggregator != aggregate::NO_AGGREGATOR
+    }
+
+    /// Number of matrices stored in the file.
+    pub fn n_planes(&self) -> usize {
+        match (self.planar, self.wav, self.with_alpha) {
+            (false, _, _) => 1,
+            (true, Some((h, _)), _) => h.channel_count as usize,
+            (true, None, false) => 3,
+            (true, None, true) => 4,
+        }
+    }
+
+    /// Size in bytes of one stored float value.
+    pub fn value_size(&self) -> usize {
+        if self.use_f64 { 8 } else { 4 }
+    }
+
+    /// Size in bytes of one stored triplet `sv_i`, `u_i`, `v_t_i`.
+    pub fn triplet_size(&self, height: usize, width: usize) -> usize {
+        if self.quantization == 0 {
+            (1 + height + width) * self.value_size()
+        } else {
+            let bits = self.quantization as usize;
+            3 * self.value_size() + (height * bits + 7) / 8 
+                                  + (width * bits + 7) / 8
+        }
+    }
+
+    /// Period used by `entropy::compress`: the number of bytes after which
+    /// the stored values are aligned again.
+    pub fn symbol_period(&self) -> usize {
+        match self.quantization {
+            0 => self.value_size(),
+            12 => 3,
+            b => b as usize / 8,
+        }
+    }
+
+    fn extensions(&self) -> u8 {
+        let extensions = 
+            if self.tolerance.is_some() { EXT_TOLERANCE } else { 0x0 } |
+            if self.layout != layout::SQUARE { EXT_LAYOUT } else { 0x0 } |
+            if self.segment_length.is_some() { EXT_SEGMENTS } else { 0x0 };
+        extensions
+    }
+
+    /// Number of segments of a segmented sound, 1 otherwise.
+    pub fn n_segments(&self) -> usize {
+        match (self.segment_length, self.wav) {
+            (Some(l), Some((h, n))) => {
+                let frames = n as usize / (h.channel_count as usize).max(1);
+                ((frames + l as usize - 1) / l as usize).max(1)
+            },
+            _ => 1
+        }
+    }
+
+    /// Number of bytes of this header in a file of its version.
+    pub fn size(&self) -> usize {
+        let fields = match self.version {
+            0 => 1,
+            1 => MAGIC.len() + 2,
+            2 => MAGIC.len() + 3,
+            3..=6 => MAGIC.len() + 4,
+            7 | 8 => MAGIC.len() + 5,
+            _ => MAGIC.len() + 6 + if self.tolerance.is_some() { 4 } else { 0 }
+                                 + if self.layout != layout::SQUARE { 5 } 
+                                   else { 0 }
+                                 + if self.segment_length.is_some() { 4 } 
+                                   else { 0 },
+        };
+        fields + if self.is_sound { WAV_HEADER_SIZE } else { 0 }
+    }
+
+    /// Number of bytes of a file besides the stored triplets: this header,
+    /// the dimensions of the matrix (with the table of ranks of the 
+    /// `n_blocks` tiles if it is tiled) and the length of the compressed 
+    /// body.
+    pub fn overhead(&self, n_blocks: Option<usize>) -> usize {
+        let dimensions = match n_blocks {
+            Some(n) => 4 * 4 + 4 * n,
+            None => 3 * 4
+        };
+        self.size() + dimensions + if self.entropy_coded { 4 } else { 0 }
+    }
+
+    /// Writes the header, always using the current `FORMAT_VERSION`.
+    pub fn write(&self, fw: &mut FileWriter) -> Result<(), Error> {
+        self.validate()?;
+
+        fw.write_all(&MAGIC)?;
+        fw.write_u8(FORMAT_VERSION)?;
+        fw.write_u8(self.flags())?;
+        fw.write_u8(self.aggregator)?;
+        fw.write_u8(self.quantization)?;
+        fw.write_u8(self.colour_space)?;
+        fw.write_u8(self.extensions())?;
+        if let Some(t) = self.tolerance {
+            fw.write_u32(t)?;
+        }
+        if self.layout != layout::SQUARE {
+            fw.write_u8(self.layout)?;
+            fw.write_u32(self.frame_length)?;
+        }
+        if let Some(l) = self.segment_length {
+            fw.write_u32(l)?;
+        }
+
+        if let Some(h) = self.wav {
+            let x: [u8; 16] = h.0.into();
+            fw.write_all(&x)?;
+            fw.write_u32(h.1)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads and validates a header, accepting every version up to
+    /// `FORMAT_VERSION` as well as the legacy files without signature.
+    pub fn read(fr: &mut FileReader) -> Result<Self, Error> {
+        let first = fr.read_u8()?;
+
+        let (version, flags) = if first == MAGIC[0] {
+            for i in 1..MAGIC.len() {
+                if fr.read_u8()? != MAGIC[i] {
+                    return Err(Error::NotASVDFile);
+                }
+            }
+            let version = fr.read_u8()?;
+            if version == 0 || version > FORMAT_VERSION {
+                return Err(Error::UnsupportedVersion(version));
+            }
+            (version, fr.read_u8()?)
+        } else if first & !LEGACY_FLAGS == 0 {
+            (0, first)
+        } else {
+            return Err(Error::NotASVDFile);
+        };
+
+        if flags & !known_flags(version) != 0 {
+            return Err(Error::InvalidHeader("unknown flags"));
+        }
+
+        // Before version 2, `Aggregator1` was the only one that could be
+        // identified.
+        let aggregator = if version >= 2 {
+            fr.read_u8()?
+        } else if flags & FLAG_AGGREGATE != 0 {
+            1
+        } else {
+            aggregate::NO_AGGREGATOR
+        };
+        if (flags & FLAG_AGGREGATE != 0) != 
+           (aggregator != aggregate::NO_AGGREGATOR) {
+            return Err(Error::InvalidHeader("inconsistent aggregation flag"));
+        }
+
+        let quantization = if version >= 3 { fr.read_u8()? } else { 0 };
+        let colour_space = if version >= 7 { fr.read_u8()? } 
+                           else { colour::RGB };
+        let extensions = if version >= 9 { fr.read_u8()? } else { 0 };
+        if extensions & !KNOWN_EXTENSIONS != 0 {
+            return Err(Error::InvalidHeader("unknown extensions"));
+        }
+        let tolerance = if extensions & EXT_TOLERANCE != 0 {
+            Some(fr.read_u32()?)
+        } else { None };
+        let (layout, frame_length) = if extensions & EXT_LAYOUT != 0 {
+            (fr.read_u8()?, fr.read_u32()?)
+        } else { (layout::SQUARE, 0) };
+        let segment_length = if extensions & EXT_SEGMENTS != 0 {
+            Some(fr.read_u32()?)
+        } else { None };
+
+        let is_sound = flags & FLAG_SOUND != 0;
+        let wav = if is_sound {
+            let mut header_raw = [0_u8; 16];
+            for i in 0..16 {
+                header_raw[i] = fr.read_u8()?;
+            }
+            let n = fr.read_u32()?;
+            Some((header_raw.into(), n))
+        } else { None };
+
+        let header = FileHeader {
+            version,
+            is_sound,
+            use_f64:    flags & FLAG_F64 != 0,
+            with_alpha: flags & FLAG_ALPHA != 0,
+            aggregator,
+            quantization,
+            entropy_coded: flags & FLAG_ENTROPY != 0,
+            tiled: flags & FLAG_TILED != 0,
+            planar: flags & FLAG_PLANAR != 0,
+            colour_space,
+            lossless: flags & FLAG_LOSSLESS != 0,
+            tolerance,
+            layout,
+            frame_length,
+            segment_length,
+            wav,
+        };
+        header.validate()?;
+
+        Ok(header)
+    }
+
+    /// Checks that the flags are consistent with each other and with the
+    /// embedded WAV header.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.is_sound != self.wav.is_some() {
+            return Err(Error::InvalidHeader("missing WAV header"));
+        }
+
+        if self.aggregate() && aggregate::from_id(self.aggregator).is_none() {
+            return Err(Error::InvalidHeader("unknown aggregator"));
+        }
+
+        if self.planar && (!self.tiled || self.aggregate()) {
+            return Err(Error::InvalidHeader("inconsistent planar flag"));
+        }
+
+        if ColourSpace::from_id(self.colour_space).is_none() {
+            return Err(Error::InvalidHeader("unknown colour space"));
+        }
+        if self.colour_space != colour::RGB && !self.planar {
+            return Err(Error::InvalidHeader("colour space without planes"));
+        }
+        let channels = self.wav.map(|h| h.0.channel_count);
+        let valid_space = match self.colour_space {
+            colour::YCBCR => !self.is_sound,
+            colour::MID_SIDE => channels == Some(2),
+            _ => true
+        };
+        if !valid_space {
+            return Err(Error::InvalidHeader("colour space of other content"));
+        }
+
+        match Layout::from_id(self.layout, self.frame_length) {
+            None => return Err(Error::InvalidHeader("unknown layout")),
+            Some(Layout::Square) => {},
+            Some(l) if !self.is_sound || (self.lossless && !l.is_exact()) =>
+                return Err(Error::InvalidHeader("layout of other content")),
+            Some(_) => {},
+        }
+
+        match self.segment_length {
+            Some(0) => return Err(Error::InvalidHeader("empty segments")),
+            Some(_) if !self.is_sound || !self.tiled =>
+                return Err(Error::InvalidHeader("inconsistent segments")),
+            _ => {}
+        }
+
+        if self.lossless && self.tolerance.is_some() {
+            return Err(Error::InvalidHeader("lossless file with a tolerance"));
+        }
+
+        if self.quantization != 0 && 
+           !QUANTIZATION_BITS.contains(&self.quantization) {
+            return Err(Error::InvalidHeader("unsupported quantization"));
+        }
+
+        if let Some((h, n_samples)) = self.wav {
+            if self.with_alpha {
+                return Err(Error::InvalidHeader("alpha channel in sound"));
+            }
+            match (h.bits_per_sample, self.float_samples()) {
+                (8, false) | (16, false) | (24, false) | (32, true) => {},
+                _ => return Err(Error::InvalidHeader("unsupported bit depth")),
+            }
+            if self.float_samples() && (self.lossless || 
+                                        self.tolerance.is_some() ||
+                                        self.colour_space != colour::RGB) {
+                return Err(Error::InvalidHeader("lossless float samples"));
+            }
+            if h.channel_count == 0 || n_samples == 0 {
+                return Err(Error::InvalidHeader("empty WAV header"));
+            }
+            if self.planar && (h.channel_count < 2 ||
+                               n_samples % h.channel_count as u32 != 0) {
+                return Err(Error::InvalidHeader("inconsistent planar flag"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the dimensions `(n, height, width)` of the stored matrix
+    /// against this header, and that `remaining` bytes are enough to hold the
+    /// `n` triplets (if they are not compressed).
+    pub fn validate_dimensions(&self, n: usize, height: usize, width: usize,
+                               remaining: u64) -> Result<(), Error> {
+
+        self.validate_shape(height, width)?;
+        if n == 0 || n > height.min(width) {
+            return Err(Error::InvalidHeader("invalid number of triplets"));
+        }
+
+        let needed = (n * self.triplet_size(height, width)) as u64;
+        if !self.entropy_coded && remaining < needed {
+            return Err(Error::InvalidHeader("file is truncated"));
+        }
+
+        Ok(())
+    }
+
+    /// Checks the dimensions of a tiled matrix and of its tiles.
+    pub fn validate_tiling(&self, height: usize, width: usize,
+                           tile_height: usize, tile_width: usize)
+        -> Result<(), Error> {
+
+        self.validate_shape(height, width)?;
+        if tile_height == 0 || tile_width == 0 ||
+           tile_height > height || tile_width > width {
+            return Err(Error::InvalidHeader("invalid tile dimensions"));
+        }
+        Ok(())
+    }
+
+    /// Checks the dimensions of the whole matrix.
+    fn validate_shape(&self, height: usize, width: usize) -> Result<(), Error> {
+        if height == 0 || width == 0 ||
+           height > MAX_DIMENSION || width > MAX_DIMENSION {
+            return Err(Error::InvalidHeader("invalid matrix dimensions"));
+        }
+
+        if let Some((_, n_samples)) = self.wav {
+            // segments are smaller than the sound, the decoder checks them
+            if self.segment_length.is_none() && 
+               (n_samples as usize) > height * width * self.n_planes() {
+                return Err(Error::InvalidHeader("too many samples"));
+            }
+        } else if !self.aggregate() && !self.planar && 
+                  (height % 2 != 0 || width % 2 != 0) {
+            return Err(Error::InvalidHeader("odd dimensions without aggregation"));
+        }
+
+        Ok(())
+    }
+}
+
+#[allow(dead_code, unused_imports)]
+mod tests {
+    use super::{FileHeader, MAGIC, FORMAT_VERSION};
+    use crate::{
+        Error,
+        read::FileReader,
+        write::FileWriter,
+        encode::Options,
+        colour::ColourSpace,
+        layout::Layout,
+    };
+    use wav::Header as WavHeader;
+
+    fn read(bytes: &[u8]) -> Result<FileHeader, Error> {
+        FileHeader::read(&mut FileReader::from_bytes(bytes.to_vec()))
+    }
+
+    fn bytes_of(header: &FileHeader) -> Vec<u8> {
+        let mut fw = FileWriter::in_memory();
+        header.write(&mut fw).unwrap();
+        fw.into_bytes()
+    }
+
+    fn error(bytes: &[u8]) -> String {
+        match read(bytes) {
+            Ok(_) => String::from("no error"),
+            Err(e) => format!("{:?}", e),
+        }
+    }
+
+    fn sound_header() -> FileHeader {
+        let mut options = Options::default();
+        options.is_wav = true;
+        options.planar = true;
+        options.colour_space = ColourSpace::MidSide;
+        options.quantization = Some(12);
+        options.entropy_coding = true;
+        options.tolerance = Some(3);
+        options.layout = Layout::Hankel(30);
+        options.segment_length = Some(400);
+        let wav = Some((WavHeader::new(1, 2, 44100, 16), 1000));
+        FileHeader::from_options(&options, wav)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let header = sound_header();
+        let bytes = bytes_of(&header);
+        assert_eq!(bytes.len(), header.size());
+        let read_header = read(&bytes).unwrap();
+        assert_eq!(read_header.version, FORMAT_VERSION);
+        assert_eq!(read_header.tolerance, Some(3));
+        assert_eq!(read_header.layout(), Layout::Hankel(30));
+        assert_eq!(read_header.segment_length, Some(400));
+        assert_eq!(read_header.wav.map(|h| h.1), Some(1000));
+        assert_eq!(bytes_of(&read_header), bytes);
+
+        let header = FileHeader::from_options(&Options::default(), None);
+        let bytes = bytes_of(&header);
+        assert_eq!(bytes_of(&read(&bytes).unwrap()), bytes);
+    }
+
+    #[test]
+    fn test_rejected_headers() {
+        assert_eq!(error(b"SVDx\x0a\x00"), "NotASVDFile");
+        assert_eq!(error(&[0x30]), "NotASVDFile");
+
+        let with_version = |version: u8, rest: &[u8]| {
+            let mut bytes = MAGIC.to_vec();
+            bytes.push(version);
+            bytes.extend_from_slice(rest);
+            bytes
+        };
+        assert_eq!(error(&with_version(0, &[0, 0])), "UnsupportedVersion(0)");
+        assert_eq!(error(&with_version(FORMAT_VERSION + 1, &[0, 0])),
+                   format!("UnsupportedVersion({})", FORMAT_VERSION + 1));
+        assert_eq!(error(&with_version(4, &[0x20, 0, 0])), 
+                   "InvalidHeader(\"unknown flags\")");
+        assert_eq!(error(&with_version(FORMAT_VERSION, &[0, 0, 0, 0, 0x80])),
+                   "InvalidHeader(\"unknown extensions\")");
+
+        // every field is needed
+        let bytes = bytes_of(&sound_header());
+        for n in 0..bytes.len() {
+            assert!(read(&bytes[..n]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_legacy_header() {
+        // a single byte of flags: f64 values of aggregated pixels
+        let header = read(&[0x5]).unwrap();
+        assert_eq!(header.version, 0);
+        assert_eq!(header.size(), 1);
+        assert!(header.use_f64 && header.aggregate() && !header.is_sound);
+        assert_eq!(header.aggregator, 1);
+        assert_eq!(header.quantization, 0);
+        assert_eq!(error(&[0x15]), "NotASVDFile");
+    }
+}
@@ -0,0 +1,391 @@
+//! Description of a compressed file (header, dimensions, singular values and
+//! size of every section) without decoding it.
+
+use super::{
+    Error,
+    read::FileReader,
+    encode::{Options, singular_values, plot_if_asked},
+    decode::{
+        ReadVectors,
+        read_file_header,
+        read_file_dimensions,
+        read_payload,
+        read_vectors_f64,
+        read_vectors_f32,
+    },
+    header::FileHeader,
+    tile::{Tiling, read_tiles},
+    colour::ColourSpace,
+    layout::{self, Layout},
+    residual::read_residual,
+    correction::Correction,
+    sweep::json_number,
+};
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+use nalgebra::RealField;
+
+use std::cmp::Ordering;
+
+pub enum InspectFormat {
+    Text,
+    Json
+}
+
+/// What a compressed file holds.
+pub struct Inspection {
+    pub header: FileHeader,
+    /// Dimensions of the matrix (of every plane in planar files, of the
+    /// first segment of segmented sounds).
+    pub height: usize,
+    pub width: usize,
+    pub tiling: Option<Tiling>,
+    /// Singular values of every tile, plane after plane and segment after
+    /// segment (of the whole matrix if the file is not tiled).
+    pub spectra: Vec<Vec<f64>>,
+    /// Size in bytes of every part of the file, in the order they are
+    /// stored (summed over the segments).
+    pub sections: Vec<(&'static str, u64)>,
+    /// Size in bytes of the triplets before entropy coding.
+    pub raw_triplets: u64,
+}
+
+impl Inspection {
+    pub fn n_triplets(&self) -> usize {
+        self.spectra.iter().map(|s| s.len()).sum()
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.sections.iter().map(|&(_, n)| n).sum()
+    }
+}
+
+/// Writes the description of the compressed file `input` in `output`, on
+/// the standard output if it is "-". The stored singular values are drawn if
+/// `options` ask for a plot.
+pub fn inspect(input: &str, output: &str, options: &Options,
+               format: InspectFormat) -> Result<(), Error> {
+
+    let inspection = inspect_file(input)?;
+
+    let mut values: Vec<f64> = inspection.spectra.concat();
+    values.sort_by(|a, b| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+    let energy = values.iter().map(|s| s * s).sum();
+    plot_if_asked(&values, values.len(), energy, options)?;
+
+    let mut out: Box<dyn Write> = if output == "-" {
+        Box::new(io::stdout())
+    } else {
+        Box::new(File::create(Path::new(output))?)
+    };
+    match format {
+        InspectFormat::Text => write_text(&mut out, &inspection)?,
+        InspectFormat::Json => write_json(&mut out, &inspection)?,
+    }
+
+    Ok(())
+}
+
+/// Reads the compressed file `input`, checking it the same way `decode`
+/// does.
+pub fn inspect_file(input: &str) -> Result<Inspection, Error> {
+    let (header, mut fr) = read_file_header(input)?;
+
+    let mut sections = vec![("header", header.size() as u64)];
+    let mut first = None;
+    let mut spectra = Vec::new();
+    let mut raw_triplets = 0;
+    let mut left = header.wav.map_or(0, |(_, n)| n as usize);
+    for _ in 0..header.n_segments() {
+        let start = fr.remaining()?;
+        let (height, width, tiling, s) = if header.use_f64 {
+            read_spectra(&mut fr, &header, read_vectors_f64)?
+        } else {
+            read_spectra(&mut fr, &header, read_vectors_f32)?
+        };
+
+        let mut parts = match tiling {
+            Some(t) => vec![("dimensions", 4 * 4), 
+                            ("ranks", 4 * t.n_blocks() as u64)],
+            None => vec![("dimensions", 3 * 4)],
+        };
+        if header.entropy_coded {
+            parts.push(("length", 4));
+        }
+        let before: u64 = parts.iter().map(|&(_, n)| n).sum();
+        parts.push(("triplets", start - fr.remaining()? - before));
+
+        // the residual or correction of a segment precedes the next one
+        if let (Some(l), Some((wav, _))) = (header.segment_length, header.wav) {
+            let n = left.min(l as usize * wav.channel_count as usize);
+            left -= n;
+            let start = fr.remaining()?;
+            if header.lossless {
+                read_residual(&mut fr, header.n_planes(), height, width)?;
+                parts.push(("residual", start - fr.remaining()?));
+            } else if let Some(t) = header.tolerance {
+                Correction::read(&mut fr, t, header.sparse_correction(), 
+                                 1, 1, n)?;
+                parts.push(("correction", start - fr.remaining()?));
+            }
+        }
+
+        for (name, n) in parts {
+            match sections.iter_mut().find(|(other, _)| *other == name) {
+                Some(section) => section.1 += n,
+                None => sections.push((name, n)),
+            }
+        }
+        let blocks = match tiling {
+            Some(t) => t.blocks(),
+            None => vec![(height, width)],
+        };
+        raw_triplets += blocks.iter().zip(s.iter())
+            .map(|(&(h, w), s)| (s.len() * header.triplet_size(h, w)) as u64)
+            .sum::<u64>();
+        spectra.extend(s);
+        first = first.or(Some((height, width, tiling)));
+    }
+    let (height, width, tiling) = first.unwrap_or((0, 0, None));
+
+    let trailing = fr.remaining()?;
+    if trailing > 0 {
+        let name = if header.lossless { "residual" }
+                   else if header.tolerance.is_some() { "correction" }
+                   else { "trailing" };
+        sections.push((name, trailing));
+    }
+
+    Ok(Inspection { header, height, width, tiling, spectra, sections,
+                    raw_triplets })
+}
+
+/// Reads the body with `read`, keeping only the singular values.
+fn read_spectra<N>(fr: &mut FileReader, header: &FileHeader,
+                   read: ReadVectors<N>)
+    -> Result<(usize, usize, Option<Tiling>, Vec<Vec<f64>>), Error>
+    where N: RealField
+    {
+    if header.tiled {
+        let (tiling, tiles) = read_tiles(fr, header, read)?;
+        let spectra = tiles.iter().map(singular_values).collect();
+        Ok((tiling.height, tiling.width, Some(tiling), spectra))
+    } else {
+        let (n, height, width) = read_file_dimensions(fr, header)?;
+        let size = n * header.triplet_size(height, width);
+        let vectors = read_payload(fr, header, size,
+                                   |r| read(r, header, n, height, width))?;
+        Ok((height, width, None, vec![singular_values(&vectors)]))
+    }
+}
+
+fn flag_names(header: &FileHeader) -> Vec<&'static str> {
+    let flags = [
+        (header.aggregate(), "aggregate"),
+        (header.with_alpha, "alpha"),
+        (header.use_f64, "f64"),
+        (header.is_sound, "sound"),
+        (header.entropy_coded, "entropy"),
+        (header.tiled, "tiled"),
+        (header.planar, "planar"),
+        (header.lossless, "lossless"),
+    ];
+    flags.iter().filter(|f| f.0).map(|f| f.1).collect()
+}
+
+fn precision(header: &FileHeader) -> String {
+    let float = if header.use_f64 { "f64" } else { "f32" };
+    match header.quantization {
+        0 => float.to_string(),
+        b => format!("{} bits ({} singular values)", b, float),
+    }
+}
+
+fn colour_space_name(header: &FileHeader) -> &'static str {
+    match ColourSpace::from_id(header.colour_space) {
+        Some(ColourSpace::Rgb) if header.is_sound => "channels",
+        Some(ColourSpace::Rgb) => "RGB",
+        Some(ColourSpace::YCbCr) => "YCbCr",
+        Some(ColourSpace::MidSide) => "mid/side",
+        None => "unknown",
+    }
+}
+
+fn layout_name(header: &FileHeader) -> &'static str {
+    match header.layout() {
+        Layout::Square => "square",
+        Layout::Frames(_) => "frames",
+        Layout::Hankel(_) => "hankel",
+        Layout::Stft(_) => "stft",
+    }
+}
+
+fn write_text(out: &mut dyn Write, i: &Inspection) -> Result<(), Error> {
+    let h = &i.header;
+    writeln!(out, "Format version: {}", h.version)?;
+    writeln!(out, "Flags: {}", flag_names(h).join(" "))?;
+    writeln!(out, "Precision: {}", precision(h))?;
+    writeln!(out, "Aggregator: {}", h.aggregator)?;
+    if let Some(t) = h.tolerance {
+        writeln!(out, "Tolerance: {}", t)?;
+    }
+    if h.layout != layout::SQUARE {
+        writeln!(out, "Layout: {} ({} samples)", layout_name(h),
+                 h.frame_length)?;
+    }
+    if let Some(l) = h.segment_length {
+        writeln!(out, "Segments: {} of {} frames", h.n_segments(), l)?;
+    }
+    if h.planar {
+        writeln!(out, "Colour space: {} ({} planes)", colour_space_name(h),
+                 h.n_planes())?;
+    }
+    writeln!(out, "Dimensions: {} x {}", i.height, i.width)?;
+    if let Some(t) = i.tiling {
+        writeln!(out, "Tiles: {} x {} ({} rows, {} columns)", t.tile_height,
+                 t.tile_width, t.n_rows(), t.n_cols())?;
+    }
+    writeln!(out, "Triplets: {}", i.n_triplets())?;
+    if let Some((wav, n_samples)) = h.wav {
+        writeln!(out, "WAV: format {}, {} channel(s), {} Hz, {} bits per \
+                       sample, {} bytes per second, {} bytes per frame, \
+                       {} samples",
+                 wav.audio_format, wav.channel_count, wav.sampling_rate,
+                 wav.bits_per_sample, wav.bytes_per_second,
+                 wav.bytes_per_sample, n_samples)?;
+    }
+
+    writeln!(out, "Bytes: {}", i.file_size())?;
+    for &(name, n) in i.sections.iter() {
+        writeln!(out, "  {:<12}{:>10}", name, n)?;
+    }
+    if h.entropy_coded {
+        writeln!(out, "  ({} bytes of triplets before entropy coding)",
+                 i.raw_triplets)?;
+    }
+
+    writeln!(out, "Singular values:")?;
+    for (b, spectrum) in i.spectra.iter().enumerate() {
+        let values: Vec<String> = spectrum.iter().map(|s| s.to_string())
+                                                 .collect();
+        if i.spectra.len() > 1 {
+            write!(out, "  tile {}: ", b)?;
+        } else {
+            write!(out, "  ")?;
+        }
+        if values.is_empty() {
+            writeln!(out, "none")?;
+        } else {
+            writeln!(out, "{}", values.join(" "))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_json(out: &mut dyn Write, i: &Inspection) -> Result<(), Error> {
+    let h = &i.header;
+    let quoted: Vec<String> = flag_names(h).iter()
+                                            .map(|f| format!("\"{}\"", f))
+                                            .collect();
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"version\": {},", h.version)?;
+    writeln!(out, "  \"flags\": [{}],", quoted.join(", "))?;
+    writeln!(out, "  \"f64\": {},", h.use_f64)?;
+    writeln!(out, "  \"quantization\": {},", h.quantization)?;
+    writeln!(out, "  \"aggregator\": {},", h.aggregator)?;
+    match h.tolerance {
+        Some(t) => writeln!(out, "  \"tolerance\": {},", t)?,
+        None => writeln!(out, "  \"tolerance\": null,")?,
+    }
+    writeln!(out, "  \"colour_space\": \"{}\",", colour_space_name(h))?;
+    writeln!(out, "  \"layout\": {{\"name\": \"{}\", \"length\": {}}},",
+             layout_name(h), h.frame_length)?;
+    match h.segment_length {
+        Some(l) => writeln!(out, "  \"segments\": {{\"count\": {}, \"length\": {}}},",
+                            h.n_segments(), l)?,
+        None => writeln!(out, "  \"segments\": null,")?,
+    }
+    writeln!(out, "  \"planes\": {},", h.n_planes())?;
+    writeln!(out, "  \"height\": {},", i.height)?;
+    writeln!(out, "  \"width\": {},", i.width)?;
+    match i.tiling {
+        Some(t) => writeln!(out, "  \"tile\": {{\"height\": {}, \"width\": {}}},",
+                            t.tile_height, t.tile_width)?,
+        None => writeln!(out, "  \"tile\": null,")?,
+    }
+    writeln!(out, "  \"triplets\": {},", i.n_triplets())?;
+    match h.wav {
+        Some((wav, n_samples)) => {
+            writeln!(out, "  \"wav\": {{\"audio_format\": {}, \
+                           \"channel_count\": {}, \"sampling_rate\": {}, \
+                           \"bytes_per_second\": {}, \"bytes_per_sample\": {}, \
+                           \"bits_per_sample\": {}, \"samples\": {}}},",
+                     wav.audio_format, wav.channel_count, wav.sampling_rate,
+                     wav.bytes_per_second, wav.bytes_per_sample,
+                     wav.bits_per_sample, n_samples)?;
+        },
+        None => writeln!(out, "  \"wav\": null,")?,
+    }
+
+    let sections: Vec<String> = i.sections.iter()
+        .map(|(name, n)| format!("\"{}\": {}", name, n))
+        .collect();
+    writeln!(out, "  \"bytes\": {{{}, \"total\": {}, \"raw_triplets\": {}}},",
+             sections.join(", "), i.file_size(), i.raw_triplets)?;
+
+    let spectra: Vec<String> = i.spectra.iter().map(|s| {
+        let values: Vec<String> = s.iter().map(|&x| json_number(x)).collect();
+        format!("[{}]", values.join(", "))
+    }).collect();
+    writeln!(out, "  \"singular_values\": [{}]", spectra.join(", "))?;
+    writeln!(out, "}}")?;
+
+    Ok(())
+}
+
+#[allow(dead_code, unused_imports)]
+mod tests {
+    use super::inspect_file;
+    use crate::{
+        write::FileWriter,
+        encode::{Options, write_vectors_header, write_payload,
+                 write_vectors_f32},
+        header::FileHeader,
+    };
+    use nalgebra::DVector;
+
+    #[test]
+    fn test_inspect() {
+        let mut options = Options::default();
+        options.use_f64 = false;
+        options.aggregator = None;
+        options.entropy_coding = true;
+        let header = FileHeader::from_options(&options, None);
+
+        let vectors = vec![
+            (10.0_f32, DVector::from_element(4, 0.5), DVector::from_element(6, 0.25)),
+            (2.5_f32, DVector::from_element(4, -0.5), DVector::from_element(6, 0.5)),
+        ];
+        let path = std::env::temp_dir().join("svd-img-test-inspect.isvd");
+        let name = path.to_str().unwrap();
+        let mut fw = FileWriter::from_name(name).unwrap();
+        write_vectors_header(&mut fw, &vectors, &header).unwrap();
+        write_payload(&mut fw, &header,
+                      |w| write_vectors_f32(w, &vectors, &header)).unwrap();
+        drop(fw);
+
+        let inspection = inspect_file(name).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((inspection.height, inspection.width), (4, 6));
+        assert_eq!(inspection.spectra, vec![vec![10.0, 2.5]]);
+        assert_eq!(inspection.raw_triplets, 2 * 11 * 4);
+        let names: Vec<_> = inspection.sections.iter().map(|s| s.0).collect();
+        assert_eq!(names, vec!["header", "dimensions", "length", "triplets"]);
+    }
+}
@@ -0,0 +1,241 @@
+//! Layouts of the samples of a sound channel in the matrix that is
+//! decomposed, and their inverses.
+
+use std::f64::consts::PI;
+
+use nalgebra::{DMatrix, Complex};
+
+/// Identifier stored in compressed files for samples put row after row in
+/// an almost square matrix.
+pub const SQUARE: u8 = 0;
+/// Identifier stored in compressed files for rows of consecutive samples.
+pub const FRAMES: u8 = 1;
+/// Identifier stored in compressed files for trajectory matrices.
+pub const HANKEL: u8 = 2;
+/// Identifier stored in compressed files for magnitudes and phases of
+/// short-time Fourier transforms.
+pub const STFT: u8 = 3;
+
+/// Largest number of values of a trajectory matrix, that holds every sample
+/// as many times as the window is long: longer sounds must be segmented.
+pub const MAX_HANKEL_VALUES: usize = 1 << 26;
+
+/// Frame length used when none is given.
+pub const DEFAULT_LENGTH: usize = 1024;
+
+/// Integer value of a phase of one radian in the `Stft` layout: the matrix
+/// holds integers for integer samples, phases in `[-π, π]` are stored in
+/// `[-4096, 4096]`.
+const PHASE_SCALE: f64 = 4096.0 / PI;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layout {
+    /// Samples row after row in an almost square matrix, padded with zeros.
+    Square,
+    /// Rows of the given number of consecutive samples, the last one padded
+    /// with zeros.
+    Frames(usize),
+    /// Trajectory matrix of singular spectrum analysis: row `i` holds the
+    /// window of the given number of samples starting at sample `i`. Each
+    /// sample is decoded as the mean of the anti-diagonal holding it.
+    Hankel(usize),
+    /// Discrete Fourier transform of the frames of the given number (a power
+    /// of two) of samples: row `i` holds the magnitudes of the frequencies
+    /// `0` to `n / 2` of frame `i`, followed by their phases.
+    Stft(usize),
+}
+
+impl Layout {
+    /// Returns the layout whose `id()` is `id` and `length()` is `length`,
+    /// if any.
+    pub fn from_id(id: u8, length: u32) -> Option<Self> {
+        let n = length as usize;
+        match (id, n) {
+            (SQUARE, _) => Some(Layout::Square),
+            (_, 0) => None,
+            (FRAMES, _) => Some(Layout::Frames(n)),
+            (HANKEL, _) => Some(Layout::Hankel(n)),
+            (STFT, _) if n.is_power_of_two() && n > 1 => Some(Layout::Stft(n)),
+            _ => None
+        }
+    }
+
+    /// Identifier stored in compressed files.
+    pub fn id(&self) -> u8 {
+        match self {
+            Layout::Square => SQUARE,
+            Layout::Frames(_) => FRAMES,
+            Layout::Hankel(_) => HANKEL,
+            Layout::Stft(_) => STFT,
+        }
+    }
+
+    /// Length of the frames or window, 0 for `Square`.
+    pub fn length(&self) -> u32 {
+        match *self {
+            Layout::Square => 0,
+            Layout::Frames(n) | Layout::Hankel(n) | Layout::Stft(n) => n as u32,
+        }
+    }
+
+    /// Whether integer samples are recovered exactly from the integer matrix.
+    pub fn is_exact(&self) -> bool {
+        match self {
+            Layout::Stft(_) => false,
+            _ => true
+        }
+    }
+
+    /// Shape of the matrix holding `n` samples.
+    pub fn shape(&self, n: usize) -> (usize, usize) {
+        match *self {
+            Layout::Square => {
+                let rows = ((n as f64).sqrt().round() as usize).max(1);
+                (rows, (n as f64 / rows as f64).ceil() as usize)
+            },
+            Layout::Frames(l) => ((n + l - 1) / l, l),
+            Layout::Hankel(l) => (n + 1 - l.min(n), l.min(n)),
+            Layout::Stft(l) => ((n + l - 1) / l, l + 2),
+        }
+    }
+
+    /// Matrix holding the `samples` of a channel.
+    pub fn to_matrix(&self, samples: &[f64]) -> DMatrix<f64> {
+        let n = samples.len();
+        let (rows, cols) = self.shape(n);
+        let at = |k: usize| if k < n { samples[k] } else { 0.0 };
+        match *self {
+            Layout::Square | Layout::Frames(_) =>
+                DMatrix::from_fn(rows, cols, |i, j| at(i * cols + j)),
+            Layout::Hankel(_) => DMatrix::from_fn(rows, cols, |i, j| at(i + j)),
+            Layout::Stft(l) => {
+                let mut m = DMatrix::zeros(rows, cols);
+                let half = l / 2 + 1;
+                for i in 0..rows {
+                    let mut frame: Vec<Complex<f64>> =
+                        (0..l).map(|t| Complex::new(at(i * l + t), 0.0))
+                              .collect();
+                    fft(&mut frame, false);
+                    for k in 0..half {
+                        let x = frame[k];
+                        m[(i, k)] = x.re.hypot(x.im);
+                        m[(i, half + k)] = x.im.atan2(x.re) * PHASE_SCALE;
+                    }
+                }
+                m
+            }
+        }
+    }
+
+    /// The `n` samples of a channel held by `matrix`, the missing values
+    /// being zeros.
+    pub fn from_matrix(&self, matrix: &DMatrix<f64>, n: usize) -> Vec<f64> {
+        let at = |i: usize, j: usize| matrix.get((i, j)).copied()
+                                            .unwrap_or(0.0);
+        let (rows, cols) = matrix.shape();
+        match *self {
+            Layout::Square | Layout::Frames(_) =>
+                (0..n).map(|k| at(k / cols, k % cols)).collect(),
+            Layout::Hankel(_) => {
+                let mut sums = vec![0.0; n];
+                let mut counts = vec![0_usize; n];
+                for i in 0..rows.min(n) {
+                    for j in 0..cols.min(n - i) {
+                        sums[i + j] += matrix[(i, j)];
+                        counts[i + j] += 1;
+                    }
+                }
+                sums.iter().zip(counts.iter())
+                    .map(|(&s, &c)| if c > 0 { s / c as f64 } else { 0.0 })
+                    .collect()
+            },
+            Layout::Stft(l) => {
+                let half = l / 2 + 1;
+                let mut samples = Vec::with_capacity(n + l);
+                for i in 0..(n + l - 1) / l {
+                    let mut frame = vec![Complex::new(0.0, 0.0); l];
+                    for k in 0..half {
+                        let x = polar(at(i, k), at(i, half + k) / PHASE_SCALE);
+                        frame[k] = x;
+                        if k > 0 && k < l - k {
+                            frame[l - k] = x.conj();
+                        }
+                    }
+                    fft(&mut frame, true);
+                    samples.extend(frame.iter().map(|x| x.re));
+                }
+                samples.truncate(n);
+                samples
+            }
+        }
+    }
+}
+
+fn polar(r: f64, theta: f64) -> Complex<f64> {
+    Complex::new(r * theta.cos(), r * theta.sin())
+}
+
+/// In place radix-2 fast Fourier transform of `x`, whose length is a power
+/// of two. The inverse one is divided by the length.
+fn fft(x: &mut [Complex<f64>], inverse: bool) {
+    let n = x.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            x.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let w = polar(1.0, sign * 2.0 * PI / len as f64);
+        for start in (0..n).step_by(len) {
+            let mut wk = Complex::new(1.0, 0.0);
+            for k in 0..len / 2 {
+                let a = x[start + k];
+                let b = x[start + k + len / 2] * wk;
+                x[start + k] = a + b;
+                x[start + k + len / 2] = a - b;
+                wk *= w;
+            }
+        }
+        len <<= 1;
+    }
+
+    if inverse {
+        for v in x.iter_mut() {
+            *v /= n as f64;
+        }
+    }
+}
+
+#[allow(dead_code, unused_imports)]
+mod tests {
+    use super::Layout;
+
+    #[test]
+    fn test_inverse_layouts() {
+        let samples: Vec<f64> = (0..1000).map(|k| ((k * 37) % 101) as f64 - 50.0)
+                                         .collect();
+        for &layout in [Layout::Square, Layout::Frames(64), Layout::Hankel(1),
+                        Layout::Hankel(30), Layout::Stft(2), Layout::Stft(128)]
+                        .iter() {
+            let m = layout.to_matrix(&samples);
+            assert_eq!(m.shape(), layout.shape(samples.len()));
+            let back = layout.from_matrix(&m, samples.len());
+            assert_eq!(back.len(), samples.len());
+            for (x, y) in samples.iter().zip(back.iter()) {
+                assert!((x - y).abs() < 1e-6, "{:?}: {} != {}", layout, x, y);
+            }
+        }
+        assert_eq!(Layout::from_id(super::STFT, 100), None);
+        assert_eq!(Layout::from_id(super::HANKEL, 30), Some(Layout::Hankel(30)));
+    }
+}
@@ -3,16 +3,32 @@ mod write;
 mod encode;
 mod decode;
 mod aggregate;
+mod header;
+mod entropy;
+mod svd;
+mod colour;
+mod tile;
+mod metrics;
+mod sweep;
+mod inspect;
+mod plot;
+mod residual;
+mod correction;
+mod layout;
+mod segment;
 
 extern crate clap;
 use clap::{Arg, App};
-use encode::{encode, fuck_up, Options, CompressionPolicy};
+use encode::{encode, fuck_up, refine, Options, CompressionPolicy};
 use decode::{decode, reduce};
+use sweep::{sweep, ReportFormat};
+use inspect::{inspect, InspectFormat};
+use metrics::compare;
 
 use std::io::Error as IOError;
 
 enum ActionTypes {
-    Encode, Decode, Reduce, FuckUp
+    Encode, Decode, Reduce, Refine, FuckUp, Sweep, Inspect, Compare
 }
 
 impl std::cmp::PartialEq for ActionTypes {
@@ -30,9 +46,25 @@ impl std::cmp::PartialEq for ActionTypes {
                 ActionTypes::Reduce => true,
                 _ => false,
             },
+            ActionTypes::Refine => match other {
+                ActionTypes::Refine => true,
+                _ => false,
+            },
             ActionTypes::FuckUp => match other {
                 ActionTypes::FuckUp => true,
                 _ => false
+            },
+            ActionTypes::Sweep => match other {
+                ActionTypes::Sweep => true,
+                _ => false
+            },
+            ActionTypes::Inspect => match other {
+                ActionTypes::Inspect => true,
+                _ => false
+            },
+            ActionTypes::Compare => match other {
+                ActionTypes::Compare => true,
+                _ => false
             }
         }
     }
@@ -48,8 +80,10 @@ pub enum Error {
     FileWriteError(IOError),
     ImageReadError,
     ImageFormatError,
+    NotASVDFile, UnsupportedVersion(u8), InvalidHeader(&'static str),
     SVDError, NoSVDResult, 
-    NTooSmall, RatioTooRestrictive, NotEnoughVectorsInSource
+    NTooSmall, RatioTooRestrictive, NotEnoughVectorsInSource,
+    UnsupportedMode(&'static str), DimensionMismatch, NothingToRefine
 }
 
 impl std::convert::From<IOError> for Error {
@@ -80,6 +114,43 @@ fn app_args() -> clap::ArgMatches<'static> {
             .help("Set the output file name.")
             .required(true)
             .index(2))
+        .arg(Arg::with_name("mode-refine")
+            .help("Sets the mode to refine (add vectors to an already \
+                   compressed file, computed from the original file given \
+                   with --original, in each tile of a tiled file)")
+            .short("U")
+            .long("refine")
+            .requires("original")
+            .conflicts_with_all(&["mode-encode", "mode-decode", 
+                                  "mode-reduce"]))
+        .arg(Arg::with_name("original")
+            .help("Sets the file the compressed input was computed from, \
+                   used by --refine.")
+            .short("O")
+            .long("original")
+            .takes_value(true))
+        .arg(Arg::with_name("lossless")
+            .help("Stores the difference between the input and its \
+                   approximation too, so that decoding gives back the exact \
+                   input. (clashes with --max-size).")
+            .short("L")
+            .long("lossless")
+            .conflicts_with("max-size"))
+        .arg(Arg::with_name("tolerance")
+            .help("Stores a correction of the samples (channels of the \
+                   pixels or audio samples) whose decoded value differs from \
+                   the input one by more than the given value, so that none \
+                   does. (clashes with --lossless and --max-size).")
+            .short("T")
+            .long("tolerance")
+            .takes_value(true)
+            .conflicts_with_all(&["lossless", "max-size"]))
+        .arg(Arg::with_name("rank")
+            .help("When decoding, uses only the given number of vectors (of \
+                   each tile), the first ones stored.")
+            .short("k")
+            .long("rank")
+            .takes_value(true))
         .arg(Arg::with_name("mode-encode")
             .help("Sets the mode to encode (clashes with -d) (default)")
             .short("e")
@@ -91,7 +162,9 @@ fn app_args() -> clap::ArgMatches<'static> {
             .conflicts_with("mode-encode"))
         .arg(Arg::with_name("mode-reduce")
             .help("Sets the mode to reduce (compress an already compressed \
-                   file even more)")
+                   file even more). --psnr, --max-error and --energy are \
+                   then measured against the stored approximation, not \
+                   against the original.")
             .short("r")
             .long("reduce")
             .conflicts_with("mode-encode")
@@ -104,11 +177,45 @@ fn app_args() -> clap::ArgMatches<'static> {
             .takes_value(true))
         .arg(Arg::with_name("compression-%")
             .help("Sets the compression ratio, in percentage, compared to the \
-                   uncompressed RGBA image. (clashes with -n option).")
+                   uncompressed RGBA image, or to the compressed input when \
+                   reducing. (clashes with -n option).")
             .short("p")
             .long("compression-%")
             .takes_value(true)
             .conflicts_with("num-vectors"))
+        .arg(Arg::with_name("psnr")
+            .help("Stores the fewest vectors giving at least the given PSNR, \
+                   in dB. Images need -P or -s, as the error of aggregated \
+                   pixels does not tell the one of their channels. (clashes \
+                   with -n and -p options).")
+            .long("psnr")
+            .takes_value(true)
+            .conflicts_with_all(&["num-vectors", "compression-%"]))
+        .arg(Arg::with_name("max-error")
+            .help("Stores the fewest vectors giving at most the given \
+                   relative error (Frobenius norm of the error over the one \
+                   of the matrix). (clashes with -n, -p and --psnr options).")
+            .long("max-error")
+            .takes_value(true)
+            .conflicts_with_all(&["num-vectors", "compression-%", "psnr"]))
+        .arg(Arg::with_name("energy")
+            .help("Stores the fewest vectors whose squared singular values \
+                   add up to at least the given percentage of the energy of \
+                   the matrix (e.g. 99.5). (clashes with -n, -p, --psnr and \
+                   --max-error options).")
+            .long("energy")
+            .takes_value(true)
+            .conflicts_with_all(&["num-vectors", "compression-%", "psnr", 
+                                  "max-error"]))
+        .arg(Arg::with_name("max-size")
+            .help("Stores as many vectors as possible in a file of at most \
+                   the given number of bytes. (clashes with -n, -p, --psnr, \
+                   --max-error and --energy options).")
+            .short("m")
+            .long("max-size")
+            .takes_value(true)
+            .conflicts_with_all(&["num-vectors", "compression-%", "psnr", 
+                                  "max-error", "energy"]))
         .arg(Arg::with_name("type-f32")
             .help("Sets the type used to represent float values to f32 \
                   (simple precision)")
@@ -120,17 +227,94 @@ fn app_args() -> clap::ArgMatches<'static> {
             .short("8")
             .long("double-precision")
             .conflicts_with("type-f32"))
+        .arg(Arg::with_name("quantize")
+            .help("Stores the singular vectors as integers of the given \
+                   number of bits (8, 12 or 16) instead of floats. Singular \
+                   values keep the full precision.")
+            .short("q")
+            .long("quantize")
+            .takes_value(true)
+            .possible_values(&["8", "12", "16"]))
+        .arg(Arg::with_name("entropy")
+            .help("Compresses the stored vectors with an entropy coder, \
+                   best used with -q.")
+            .short("z")
+            .long("entropy"))
+        .arg(Arg::with_name("planar")
+            .help("Decomposes each channel of the image separately, each one \
+                   having its own number of vectors. Clashes with -s and -A.")
+            .short("P")
+            .long("planar")
+            .conflicts_with_all(&["no-aggregate", "aggregator-id"]))
+        .arg(Arg::with_name("ycbcr")
+            .help("Converts the image to YCbCr before decomposing each \
+                   channel separately (implies -P), most of the vectors \
+                   being kept for the luma.")
+            .short("Y")
+            .long("ycbcr")
+            .conflicts_with_all(&["no-aggregate", "aggregator-id"]))
+        .arg(Arg::with_name("mid-side")
+            .help("Decomposes the mid and side of a stereo sound instead of \
+                   its left and right channels.")
+            .short("M")
+            .long("mid-side")
+            .conflicts_with("ycbcr"))
+        .arg(Arg::with_name("layout")
+            .help("Sets how the samples of each channel of a sound are laid \
+                   out in its matrix: row after row in an almost square \
+                   matrix (square, the default), in rows of --frame-length \
+                   samples (frames), in the trajectory matrix of windows of \
+                   --frame-length samples (hankel), or as the magnitudes and \
+                   phases of the spectra of frames of --frame-length samples \
+                   (stft, clashes with --lossless).")
+            .long("layout")
+            .takes_value(true)
+            .possible_values(&["square", "frames", "hankel", "stft"]))
+        .arg(Arg::with_name("frame-length")
+            .help("Sets the number of samples of the frames or windows of \
+                   --layout (1024 by default, a power of two for stft).")
+            .long("frame-length")
+            .takes_value(true)
+            .requires("layout"))
+        .arg(Arg::with_name("segment-length")
+            .help("Splits a sound in segments of the given number of frames \
+                   (samples of every channel), each one being decomposed \
+                   separately and stored after the previous one, so that \
+                   long sounds are encoded and decoded one segment at a \
+                   time.")
+            .long("segment-length")
+            .takes_value(true)
+            .conflicts_with_all(&["fuck-up", "sweep", "max-size"]))
+        .arg(Arg::with_name("tile-size")
+            .help("Splits the matrix in square tiles of the given size, each \
+                   one having its own number of vectors, chosen to respect \
+                   the compression ratio (or the size of -n vectors of the \
+                   whole matrix).")
+            .short("t")
+            .long("tile-size")
+            .takes_value(true))
         .arg(Arg::with_name("epsilon")
             .help("Sets the tolerance used to determine if a value converged \
                    to zero (simple precision) used to compute the SVD")
             .short("E")
             .long("epsilon")
             .takes_value(true))
+        .arg(Arg::with_name("randomized")
+            .help("Computes only the kept singular vectors with a \
+                   randomized algorithm instead of the full SVD. Much faster \
+                   on large inputs, at the cost of a slightly less accurate \
+                   approximation.")
+            .short("R")
+            .long("randomized"))
         .arg(Arg::with_name("n-iter")
             .help("Sets the maximum number of iteration when computing the \
-SVD, 0 for iterating until convergence")
+SVD, 0 for iterating until convergence. If the limit is reached, the vectors \
+are computed by as many steps of orthogonal iteration, a warning is printed \
+and these partially converged vectors are used. Not available with the \
+randomized method, that does a fixed number of iterations.")
             .short("i")
             .long("n-iter")
+            .conflicts_with("randomized")
             .takes_value(true))
         .arg(Arg::with_name("wav-input")
             .help("Consider the input as WAV file, independently of the file \
@@ -148,8 +332,9 @@ SVD, 0 for iterating until convergence")
             .short("s")
             .long("no-aggregate"))
         .arg(Arg::with_name("aggregator-id")
-            .help("Sets the id of the aggregator to use. Its my stuff so \
-                  shut up pls, I do what I want for testing.")
+            .help("Sets the id of the aggregator to use: 1 interleaves the \
+                   bits of the channels (default), 2 puts their bytes side \
+                   by side. It is stored in the compressed file.")
             .short("A")
             .long("aggregator-id")
             .takes_value(true)
@@ -159,6 +344,51 @@ SVD, 0 for iterating until convergence")
                    approximated result in the output.")
             .short("f")
             .long("fuck-up"))
+        .arg(Arg::with_name("sweep")
+            .help("Reads the input, computes the SVD once and writes in the \
+                   output the size of the compressed file and the MSE, PSNR \
+                   and SSIM of the decoded result for every number of \
+                   vectors (up to -n). Only for a matrix decomposed at once: \
+                   not with -t or -P, nor for a sound of several channels \
+                   (whose channels are decomposed separately).")
+            .short("S")
+            .long("sweep")
+            .conflicts_with_all(&["mode-encode", "mode-decode", "mode-reduce", 
+                                  "fuck-up"]))
+        .arg(Arg::with_name("inspect")
+            .help("Reads the compressed input and writes in the output (on \
+                   the standard output if it is \"-\") its header, \
+                   dimensions, singular values and the size of each part \
+                   of the file.")
+            .short("I")
+            .long("inspect")
+            .conflicts_with_all(&["mode-encode", "mode-decode", "mode-reduce", 
+                                  "fuck-up", "sweep"]))
+        .arg(Arg::with_name("compare")
+            .help("Prints the MSE, PSNR, largest error and SSIM of the \
+                   output (an image or a sound) with respect to the input.")
+            .short("C")
+            .long("compare")
+            .conflicts_with_all(&["mode-encode", "mode-decode", "mode-reduce", 
+                                  "fuck-up", "sweep", "inspect"]))
+        .arg(Arg::with_name("heatmap")
+            .help("With -f or -C, draws in the given PNG file the error of \
+                   each pixel, from blue (none) to red (the largest one).")
+            .long("heatmap")
+            .takes_value(true))
+        .arg(Arg::with_name("plot")
+            .help("Draws in the given PNG file the singular values on a log \
+                   scale, the fraction of the energy they keep, and where \
+                   the kept ones stop.")
+            .long("plot")
+            .takes_value(true))
+        .arg(Arg::with_name("format")
+            .help("Sets the format of the report of --sweep (csv, the \
+                   default, or json) or of --inspect (text, the default, or \
+                   json).")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["csv", "json", "text"]))
         .get_matches()
 }
 
@@ -172,8 +402,16 @@ fn main() -> Result<(), Error> {
                              { ActionTypes::Decode }
                         else if matches.is_present("mode-reduce")
                              { ActionTypes::Reduce }
+                        else if matches.is_present("mode-refine")
+                             { ActionTypes::Refine }
                         else if matches.is_present("fuck-up")
                              { ActionTypes::FuckUp }
+                        else if matches.is_present("sweep")
+                             { ActionTypes::Sweep }
+                        else if matches.is_present("inspect")
+                             { ActionTypes::Inspect }
+                        else if matches.is_present("compare")
+                             { ActionTypes::Compare }
                         else { ActionTypes::Encode };
 
     let mut options = Options::default();
@@ -195,70 +433,209 @@ fn main() -> Result<(), Error> {
             return Ok(());
         }
     };
+    if let Some(bits) = matches.value_of("quantize") {
+        options.quantization = Some(bits.parse::<u8>().unwrap());
+    }
+    options.entropy_coding = matches.is_present("entropy");
+    if matches.is_present("randomized") {
+        options.svd_method = svd::SVDMethod::randomized();
+    }
+    if let Some(size_str) = matches.value_of("tile-size") {
+        options.tile_size = match size_str.parse::<usize>() {
+            Ok(n) if n > 0 => Some(n),
+            _ => {
+                println!("Invalid tile size: {}", size_str);
+                return Ok(());
+            }
+        };
+    }
     options.is_wav = matches.is_present("wav-input");
+    options.planar = matches.is_present("planar") || 
+                     matches.is_present("ycbcr");
+    if matches.is_present("ycbcr") {
+        options.colour_space = colour::ColourSpace::YCbCr;
+    }
+    if matches.is_present("mid-side") {
+        options.colour_space = colour::ColourSpace::MidSide;
+    }
+    let length = match matches.value_of("frame-length") {
+        Some(l_str) => match l_str.parse::<u32>() {
+            Ok(l) => l,
+            Err(e) => {
+                println!("Invalid frame length: {:?}", e);
+                return Ok(());
+            }
+        },
+        None => layout::DEFAULT_LENGTH as u32,
+    };
+    if let Some(name) = matches.value_of("layout") {
+        let id = match name {
+            "frames" => layout::FRAMES,
+            "hankel" => layout::HANKEL,
+            "stft" => layout::STFT,
+            _ => layout::SQUARE,
+        };
+        options.layout = match layout::Layout::from_id(id, length) {
+            Some(l) => l,
+            None => {
+                println!("Invalid frame length for {}: {}", name, length);
+                return Ok(());
+            }
+        };
+    }
+    if let Some(l_str) = matches.value_of("segment-length") {
+        options.segment_length = match l_str.parse::<usize>() {
+            Ok(l) if l > 0 => Some(l),
+            _ => {
+                println!("Invalid segment length: {}", l_str);
+                return Ok(());
+            }
+        };
+    }
     options.is_reduce = matches.is_present("mode-reduce");
+    options.lossless = matches.is_present("lossless");
+    if let Some(t_str) = matches.value_of("tolerance") {
+        options.tolerance = match t_str.parse::<u32>() {
+            Ok(t) => Some(t),
+            Err(e) => {
+                println!("Invalid tolerance: {:?}", e);
+                return Ok(());
+            }
+        };
+    }
+    options.spectrum_plot = matches.value_of("plot").map(String::from);
+    if let Some(k_str) = matches.value_of("rank") {
+        options.rank = match k_str.parse::<usize>() {
+            Ok(k) if k > 0 => Some(k),
+            _ => {
+                println!("Invalid rank: {}", k_str);
+                return Ok(());
+            }
+        };
+    }
+    options.error_heatmap = matches.value_of("heatmap").map(String::from);
     
     options.with_alpha = matches.is_present("with-alpha");
-    if matches.is_present("no-aggregate") {
+    if matches.is_present("no-aggregate") || options.planar {
         options.aggregator = None;
     } else if matches.is_present("aggregator-id") {
         let id = match matches.value_of("aggregator-id").unwrap().
-                               parse::<usize>() {
+                               parse::<u8>() {
             Ok(n) => n,
             Err(e) => {
                 println!("Invalid aggregator id: {:?}", e);
                 return Ok(());
             }
         };
-        if id == 2 {
-            options.aggregator = Some(Box::new(aggregate::Aggregator2));
-        } else {
-            options.aggregator = Some(Box::new(aggregate::Aggregator1));
-        }
+        options.aggregator = match aggregate::from_id(id) {
+            Some(ag) => Some(ag),
+            None => {
+                println!("Unknown aggregator id: {}", id);
+                return Ok(());
+            }
+        };
     }
 
-    options.policy = match matches.value_of("num-vectors") {
-        Some(n_str) => match n_str.parse::<usize>() {
+    options.policy = if let Some(n_str) = matches.value_of("num-vectors") {
+        match n_str.parse::<usize>() {
             Ok(n) => CompressionPolicy::with_number(n),
             Err(e) => {
                 println!("Invalid number of vectors: {:?}", e);
                 return Ok(())
             }
-        },
-        None => match matches.value_of("compression-%") {
-            Some(r_str) => match r_str.parse::<u8>() {
-                Ok(r) => {
-                    if r > 100 {
-                        println!("Compression ratio cannot exceed 100% !");
-                        return Ok(());
-                    }
-                    CompressionPolicy::with_ratio_percentage(r)
-                },
-                Err(e) => {
-                    println!("Invalid compression ratio: {:?}", e);
-                    return Ok(())
+        }
+    } else if let Some(r_str) = matches.value_of("compression-%") {
+        match r_str.parse::<u8>() {
+            Ok(r) => {
+                if r > 100 {
+                    println!("Compression ratio cannot exceed 100% !");
+                    return Ok(());
                 }
+                CompressionPolicy::with_ratio_percentage(r)
+            },
+            Err(e) => {
+                println!("Invalid compression ratio: {:?}", e);
+                return Ok(())
             }
-            None => {
-                if action_type == ActionTypes::Encode {
-                    println!("Using default compression ratio (25%).");
-                }
-                CompressionPolicy::with_ratio_percentage(25)
+        }
+    } else if let Some(db_str) = matches.value_of("psnr") {
+        match db_str.parse::<f64>() {
+            Ok(db) if db > 0.0 => CompressionPolicy::with_psnr(db),
+            _ => {
+                println!("Invalid PSNR: {}", db_str);
+                return Ok(())
             }
         }
+    } else if let Some(e_str) = matches.value_of("max-error") {
+        match e_str.parse::<f64>() {
+            Ok(e) if e >= 0.0 => CompressionPolicy::with_relative_error(e),
+            _ => {
+                println!("Invalid relative error: {}", e_str);
+                return Ok(())
+            }
+        }
+    } else if let Some(p_str) = matches.value_of("energy") {
+        match p_str.parse::<f64>() {
+            Ok(p) if p >= 0.0 && p <= 100.0 => 
+                CompressionPolicy::with_energy_percentage(p),
+            _ => {
+                println!("Invalid energy percentage: {}", p_str);
+                return Ok(())
+            }
+        }
+    } else if let Some(s_str) = matches.value_of("max-size") {
+        match s_str.parse::<u64>() {
+            Ok(s) => CompressionPolicy::with_max_size(s),
+            Err(e) => {
+                println!("Invalid file size: {:?}", e);
+                return Ok(())
+            }
+        }
+    } else {
+        if action_type == ActionTypes::Encode {
+            println!("Using default compression ratio (25%).");
+        }
+        CompressionPolicy::with_ratio_percentage(25)
     };
 
     let result = if action_type == ActionTypes::Encode {
-        encode(input, output, &mut options)
+        encode(input, output, &mut options).map(|_| ())
     }
     else if action_type == ActionTypes::Decode {
         decode(input, output, &mut options)
     }
     else if action_type == ActionTypes::FuckUp {
-        fuck_up(input, output, &mut options)
+        fuck_up(input, output, &mut options).map(|_| ())
+    }
+    else if action_type == ActionTypes::Sweep {
+        match matches.value_of("format") {
+            Some("csv") | None => 
+                sweep(input, output, &mut options, ReportFormat::Csv),
+            Some("json") => 
+                sweep(input, output, &mut options, ReportFormat::Json),
+            Some(_) => Err(Error::UnsupportedMode("report format of a sweep \
+                                                   (csv or json)"))
+        }
+    }
+    else if action_type == ActionTypes::Inspect {
+        match matches.value_of("format") {
+            Some("text") | None => 
+                inspect(input, output, &options, InspectFormat::Text),
+            Some("json") => 
+                inspect(input, output, &options, InspectFormat::Json),
+            Some(_) => Err(Error::UnsupportedMode("report format of an \
+                                                   inspection (text or json)"))
+        }
+    }
+    else if action_type == ActionTypes::Refine {
+        let original = matches.value_of("original").unwrap();
+        refine(input, output, original, &mut options)
+    }
+    else if action_type == ActionTypes::Compare {
+        compare(input, output, &mut options).map(|_| ())
     }
     else /* action_type == ActionTypes::Reduce */ {
-        reduce(input, output, &options)        
+        reduce(input, output, &mut options)        
     };
 
     match result {
@@ -267,7 +644,11 @@ fn main() -> Result<(), Error> {
                 ActionTypes::Encode => "encode",
                 ActionTypes::Decode => "decode",
                 ActionTypes::Reduce => "reduce",
+                ActionTypes::Refine => "refine",
                 ActionTypes::FuckUp => "fuck up",
+                ActionTypes::Sweep => "sweep",
+                ActionTypes::Inspect => "inspect",
+                ActionTypes::Compare => "compare",
             }, e),
         Ok(_) => {}
     }
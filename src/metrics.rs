@@ -0,0 +1,280 @@
+//! Distortion between the samples of an input file and the ones of its
+//! approximation.
+
+use super::{
+    Error,
+    encode::{Options, read_image_file, report_quality, heatmap_if_asked},
+    decode::{
+        imgbuf_from_matrix_rgb,
+        imgbuf_from_matrix_rgba,
+        imgbuf_from_planes_rgb,
+        imgbuf_from_planes_rgba,
+        sound_from_planes,
+        sound_from_float_planes,
+    },
+    layout::Layout,
+};
+
+use std::{
+    fs::File,
+    path::Path,
+};
+
+use image::{ImageBuffer, Pixel};
+
+use nalgebra::DMatrix;
+
+use wav::{
+    BitDepth as WavData,
+    Header as WavHeader,
+};
+
+/// Side of the square windows on which the SSIM is computed (clipped to the
+/// dimensions of the channels).
+const SSIM_WINDOW: usize = 7;
+
+/// Samples of an image (one matrix per channel) or of a sound (a single row).
+pub struct Samples {
+    pub channels: Vec<DMatrix<f64>>,
+    /// Largest possible value of a sample.
+    pub peak: f64,
+}
+
+/// Distortion of an approximation.
+#[derive(Clone, Copy, Debug)]
+pub struct Quality {
+    /// Mean squared error.
+    pub mse: f64,
+    /// Peak signal to noise ratio, in dB (infinite if there is no error).
+    pub psnr: f64,
+    /// Largest absolute difference between two samples.
+    pub max_error: f64,
+    /// Mean structural similarity of the channels, in `[-1, 1]`.
+    pub ssim: f64,
+}
+
+impl Quality {
+    /// Compares `decoded` to `original`, that must have the same shape.
+    pub fn between(original: &Samples, decoded: &Samples) -> Self {
+        let mse = mse(&original.channels, &decoded.channels);
+        Quality {
+            mse,
+            psnr: psnr(mse, original.peak),
+            max_error: max_error(&original.channels, &decoded.channels),
+            ssim: ssim(&original.channels, &decoded.channels, original.peak),
+        }
+    }
+
+    /// Quality of the whole of which `parts` are the qualities of the parts
+    /// and their number of samples. The SSIM is their mean weighted by the
+    /// number of samples, that ignores the windows across two parts.
+    pub fn merge(parts: &[(Quality, usize)], peak: f64) -> Self {
+        let n: usize = parts.iter().map(|&(_, n)| n).sum();
+        let mean = |f: fn(&Quality) -> f64| if n == 0 { 0.0 } else {
+            parts.iter().map(|(q, k)| f(q) * *k as f64).sum::<f64>() / n as f64
+        };
+        let mse = mean(|q| q.mse);
+        Quality {
+            mse,
+            psnr: psnr(mse, peak),
+            max_error: parts.iter().map(|(q, _)| q.max_error)
+                                   .fold(0.0, f64::max),
+            ssim: if n == 0 { 1.0 } else { mean(|q| q.ssim) },
+        }
+    }
+}
+
+pub fn mse(a: &[DMatrix<f64>], b: &[DMatrix<f64>]) -> f64 {
+    let mut sum = 0.0;
+    let mut n = 0;
+    for (x, y) in a.iter().zip(b) {
+        sum += (x - y).norm_squared();
+        n += x.len();
+    }
+    if n == 0 { 0.0 } else { sum / n as f64 }
+}
+
+pub fn max_error(a: &[DMatrix<f64>], b: &[DMatrix<f64>]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| (x - y).amax()).fold(0.0, f64::max)
+}
+
+pub fn psnr(mse: f64, peak: f64) -> f64 {
+    10.0 * (peak * peak / mse).log10()
+}
+
+/// Mean SSIM (Wang et al., 2004) of the channels, computed on every window
+/// of `SSIM_WINDOW x SSIM_WINDOW` samples with uniform weights.
+pub fn ssim(a: &[DMatrix<f64>], b: &[DMatrix<f64>], peak: f64) -> f64 {
+    if a.is_empty() {  return 1.0;  }
+    a.iter().zip(b).map(|(x, y)| ssim_channel(x, y, peak)).sum::<f64>()
+        / a.len() as f64
+}
+
+fn ssim_channel(a: &DMatrix<f64>, b: &DMatrix<f64>, peak: f64) -> f64 {
+    let (h, w) = a.shape();
+    let (wh, ww) = (SSIM_WINDOW.min(h), SSIM_WINDOW.min(w));
+    if wh == 0 || ww == 0 {  return 1.0;  }
+
+    let c1 = (0.01 * peak).powi(2);
+    let c2 = (0.03 * peak).powi(2);
+
+    let s_a  = integral(h, w, |i, j| a[(i, j)]);
+    let s_b  = integral(h, w, |i, j| b[(i, j)]);
+    let s_aa = integral(h, w, |i, j| a[(i, j)] * a[(i, j)]);
+    let s_bb = integral(h, w, |i, j| b[(i, j)] * b[(i, j)]);
+    let s_ab = integral(h, w, |i, j| a[(i, j)] * b[(i, j)]);
+
+    let n = (wh * ww) as f64;
+    let mut sum = 0.0;
+    for i in 0..=(h - wh) {
+        for j in 0..=(w - ww) {
+            let window = |s: &DMatrix<f64>| {
+                (s[(i + wh, j + ww)] - s[(i, j + ww)]
+                 - s[(i + wh, j)] + s[(i, j)]) / n
+            };
+            let (m_a, m_b) = (window(&s_a), window(&s_b));
+            let var_a = window(&s_aa) - m_a * m_a;
+            let var_b = window(&s_bb) - m_b * m_b;
+            let cov = window(&s_ab) - m_a * m_b;
+            sum += (2.0 * m_a * m_b + c1) * (2.0 * cov + c2) /
+                   ((m_a * m_a + m_b * m_b + c1) * (var_a + var_b + c2));
+        }
+    }
+    sum / ((h - wh + 1) * (w - ww + 1)) as f64
+}
+
+/// Summed-area table of `f` over a `h x w` grid.
+fn integral<F>(h: usize, w: usize, f: F) -> DMatrix<f64>
+    where F: Fn(usize, usize) -> f64
+    {
+    let mut s = DMatrix::<f64>::zeros(h + 1, w + 1);
+    for i in 0..h {
+        for j in 0..w {
+            s[(i + 1, j + 1)] = f(i, j) + s[(i, j + 1)] + s[(i + 1, j)]
+                                        - s[(i, j)];
+        }
+    }
+    s
+}
+
+/// Compares the file `approximated` to the `original` one (images or sounds
+/// of the same size), prints their distortion and draws their error if
+/// `options` ask for it.
+pub fn compare(original: &str, approximated: &str, options: &mut Options)
+    -> Result<Quality, Error> {
+
+    options.is_wav |= original.ends_with(".WAV") || original.ends_with(".wav");
+    let a = input_samples(original, options)?;
+    let b = input_samples(approximated, options)?;
+    let same_shape = a.channels.len() == b.channels.len() && 
+        a.channels.iter().zip(b.channels.iter())
+                  .all(|(x, y)| x.shape() == y.shape());
+    if !same_shape {
+        return Err(Error::DimensionMismatch);
+    }
+
+    let quality = Quality::between(&a, &b);
+    report_quality(&quality);
+    heatmap_if_asked(&a, &b, options)?;
+    Ok(quality)
+}
+
+/// Samples of the input file, as read before encoding.
+pub fn input_samples(input: &str, options: &Options) -> Result<Samples, Error> {
+    if options.is_wav {
+        let mut in_file = File::open(Path::new(input))?;
+        let (header, data) = wav::read(&mut in_file)?;
+        Ok(sound_samples(&data, header))
+    } else {
+        let img = read_image_file(input)?;
+        let channels = if options.with_alpha { channels_of(&img.into_rgba8()) }
+                       else                  { channels_of(&img.into_rgb8())  };
+        Ok(Samples { channels, peak: 255.0 })
+    }
+}
+
+/// Samples decoded from the `planes` computed with `options`, `wav` being
+/// the header of the sound, if any.
+pub fn decoded_samples(planes: &[DMatrix<i32>], options: &Options,
+                       wav: Option<(WavHeader, u32)>)
+    -> Result<Samples, Error> {
+
+    let mut planes = planes.to_vec();
+    if options.planar {
+        options.colour_space.to_rgb(&mut planes);
+    }
+    if let Some(h) = wav {
+        return Ok(sound_samples(&sound_from_planes(&planes, h, options.layout), h.0));
+    }
+
+    let channels = if options.planar {
+        if options.with_alpha { channels_of(&imgbuf_from_planes_rgba(&planes)) }
+        else                  { channels_of(&imgbuf_from_planes_rgb(&planes))  }
+    } else if options.with_alpha {
+        channels_of(&imgbuf_from_matrix_rgba(&planes[0], &options.aggregator)?)
+    } else {
+        channels_of(&imgbuf_from_matrix_rgb(&planes[0], &options.aggregator)?)
+    };
+    Ok(Samples { channels, peak: 255.0 })
+}
+
+/// Samples decoded from the `planes` of a sound with float samples.
+pub fn float_samples(planes: &[DMatrix<f32>], wav: (WavHeader, u32),
+                     layout: Layout) -> Samples {
+    sound_samples(&sound_from_float_planes(planes, wav, layout), wav.0)
+}
+
+fn channels_of<P>(img: &ImageBuffer<P, Vec<u8>>) -> Vec<DMatrix<f64>>
+    where P: Pixel<Subpixel = u8> + 'static
+    {
+    let (w, h) = img.dimensions();
+    (0..P::CHANNEL_COUNT as usize).map(|c| {
+        DMatrix::from_fn(w as usize, h as usize, |i, j| {
+            img.get_pixel(i as u32, j as u32).channels()[c] as f64
+        })
+    }).collect()
+}
+
+/// Samples of a sound, 24-bit ones being shifted back from the 3 upper bytes
+/// of the `i32` where `wav` keeps them.
+pub(crate) fn sound_samples(data: &WavData, header: WavHeader) -> Samples {
+    let values: Vec<f64> = match data {
+        WavData::Eight(v) => v.iter().map(|&x| x as f64).collect(),
+        WavData::Sixteen(v) => v.iter().map(|&x| x as f64).collect(),
+        WavData::TwentyFour(v) => v.iter().map(|&x| (x >> 8) as f64).collect(),
+        WavData::ThirtyTwoFloat(v) => v.iter().map(|&x| x as f64).collect(),
+        _ => Vec::new()
+    };
+    let peak = match data {
+        // full scale is [-1, 1]
+        WavData::ThirtyTwoFloat(_) => 1.0,
+        _ => 2_f64.powi(header.bits_per_sample as i32) - 1.0,
+    };
+    Samples {
+        channels: vec![DMatrix::from_row_slice(1, values.len(), &values)],
+        peak,
+    }
+}
+
+#[allow(dead_code, unused_imports)]
+mod tests {
+    use super::{mse, max_error, psnr, ssim};
+    use nalgebra::DMatrix;
+
+    #[test]
+    fn test_metrics() {
+        let a = vec![DMatrix::from_fn(20, 30, |i, j| ((i * 7 + j * 3) % 256) as f64)];
+        assert_eq!(mse(&a, &a), 0.0);
+        assert!((ssim(&a, &a, 255.0) - 1.0).abs() < 1.0e-9);
+
+        let b = vec![a[0].map(|x| x + 2.0)];
+        assert!((mse(&a, &b) - 4.0).abs() < 1.0e-9);
+        assert_eq!(max_error(&a, &b), 2.0);
+        assert!((psnr(4.0, 255.0) - 42.11).abs() < 0.01);
+        let s = ssim(&a, &b, 255.0);
+        assert!(s < 1.0 && s > 0.99);
+
+        let c = vec![a[0].map(|x| 255.0 - x)];
+        assert!(ssim(&a, &c, 255.0) < 0.0);
+    }
+}
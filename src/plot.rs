@@ -0,0 +1,208 @@
+//! Pictures of the singular value spectrum of a matrix, to see at a glance
+//! how compressible it is, and of the error of its approximation.
+
+use super::{
+    Error,
+    metrics::Samples,
+};
+
+use image::{Rgb, RgbImage};
+
+const WIDTH: u32 = 640;
+const HEIGHT: u32 = 400;
+/// Space left around the plotted area.
+const MARGIN: u32 = 30;
+/// Smallest singular value drawn, relative to the largest one: the log scale
+/// is clipped there.
+const DYNAMIC_RANGE: f64 = 1.0e-8;
+
+const BACKGROUND: Rgb<u8> = Rgb([255, 255, 255]);
+const AXES: Rgb<u8> = Rgb([0, 0, 0]);
+const GRID: Rgb<u8> = Rgb([220, 220, 220]);
+const SPECTRUM: Rgb<u8> = Rgb([31, 119, 180]);
+const CUMULATIVE: Rgb<u8> = Rgb([255, 127, 14]);
+const CUT: Rgb<u8> = Rgb([214, 39, 40]);
+
+/// Draws in the PNG `path` the singular values `values` (sorted by
+/// decreasing value) on a log scale, in blue, and the fraction of `energy`
+/// kept by the first ones on a linear scale from 0 to 1, in orange. The
+/// horizontal lines mark the powers of ten, the red vertical one is after
+/// the `cut` first values, that is the kept ones.
+pub fn plot_spectrum(values: &[f64], cut: usize, energy: f64, path: &str)
+    -> Result<(), Error> {
+
+    let mut img = RgbImage::from_pixel(WIDTH, HEIGHT, BACKGROUND);
+    let (left, right) = (MARGIN as f64, (WIDTH - MARGIN) as f64);
+    let (top, bottom) = (MARGIN as f64, (HEIGHT - MARGIN) as f64);
+
+    let n = values.len();
+    let x_of = |rank: f64| {
+        if n <= 1 { left } else { left + (right - left) * rank / (n - 1) as f64 }
+    };
+
+    let max = values.iter().cloned().fold(0.0, f64::max);
+    if max > 0.0 {
+        let min = values.iter().cloned().filter(|&s| s > 0.0)
+                        .fold(max, f64::min).max(max * DYNAMIC_RANGE);
+        let lo = min.log10().floor();
+        let hi = max.log10().ceil().max(lo + 1.0);
+        let y_of = |log: f64| bottom - (bottom - top) * (log - lo) / (hi - lo);
+
+        let mut decade = lo;
+        while decade <= hi {
+            let y = y_of(decade);
+            draw_line(&mut img, (left, y), (right, y), GRID);
+            decade += 1.0;
+        }
+
+        let points: Vec<(f64, f64)> = values.iter().enumerate()
+            .map(|(i, &s)| (x_of(i as f64), y_of(s.max(min).log10())))
+            .collect();
+        draw_polyline(&mut img, &points, SPECTRUM);
+    }
+
+    if energy > 0.0 {
+        let mut kept = 0.0;
+        let points: Vec<(f64, f64)> = values.iter().enumerate().map(|(i, &s)| {
+            kept += s * s;
+            (x_of(i as f64), bottom - (bottom - top) * (kept / energy).min(1.0))
+        }).collect();
+        draw_polyline(&mut img, &points, CUMULATIVE);
+    }
+
+    if cut > 0 && cut <= n {
+        let x = x_of(cut as f64 - 0.5).max(left);
+        draw_line(&mut img, (x, top), (x, bottom), CUT);
+    }
+
+    draw_line(&mut img, (left, top), (left, bottom), AXES);
+    draw_line(&mut img, (left, bottom), (right, bottom), AXES);
+
+    img.save(path).map_err(|_| Error::ImageFormatError)
+}
+
+fn draw_polyline(img: &mut RgbImage, points: &[(f64, f64)], colour: Rgb<u8>) {
+    if points.len() == 1 {
+        draw_line(img, points[0], points[0], colour);
+    }
+    for pair in points.windows(2) {
+        draw_line(img, pair[0], pair[1], colour);
+    }
+}
+
+fn draw_line(img: &mut RgbImage, from: (f64, f64), to: (f64, f64),
+             colour: Rgb<u8>) {
+    let steps = (to.0 - from.0).abs().max((to.1 - from.1).abs()).ceil()
+                                     .max(1.0) as usize;
+    for k in 0..=steps {
+        let t = k as f64 / steps as f64;
+        let x = (from.0 + t * (to.0 - from.0)).round();
+        let y = (from.1 + t * (to.1 - from.1)).round();
+        if x >= 0.0 && y >= 0.0 && (x as u32) < img.width() &&
+           (y as u32) < img.height() {
+            img.put_pixel(x as u32, y as u32, colour);
+        }
+    }
+}
+
+/// Draws in the PNG `path` the error of each pixel of `decoded`, see
+/// `heatmap_image`.
+pub fn error_heatmap(original: &Samples, decoded: &Samples, path: &str)
+    -> Result<(), Error> {
+    let (img, max) = heatmap_image(original, decoded);
+    println!("Error heatmap: red is an error of {}", max);
+    img.save(path).map_err(|_| Error::ImageFormatError)
+}
+
+/// False colour image of the largest absolute error among the channels of
+/// each pixel, from blue (no error) to red (the largest error of the image,
+/// returned as well).
+pub fn heatmap_image(original: &Samples, decoded: &Samples) -> (RgbImage, f64) {
+    let (w, h) = original.channels[0].shape();
+    let error = |x: usize, y: usize| {
+        original.channels.iter().zip(decoded.channels.iter())
+            .map(|(a, b)| (a[(x, y)] - b[(x, y)]).abs())
+            .fold(0.0, f64::max)
+    };
+    let mut max = 0.0_f64;
+    for x in 0..w {
+        for y in 0..h {
+            max = max.max(error(x, y));
+        }
+    }
+
+    let img = RgbImage::from_fn(w as u32, h as u32, |x, y| {
+        let e = error(x as usize, y as usize);
+        false_colour(if max > 0.0 { e / max } else { 0.0 })
+    });
+    (img, max)
+}
+
+/// Colour of `t` in `[0, 1]` going through blue, cyan, green, yellow and red.
+fn false_colour(t: f64) -> Rgb<u8> {
+    let channel = |centre: f64| {
+        let c = (1.5 - (4.0 * t - centre).abs()).max(0.0).min(1.0);
+        (255.0 * c).round() as u8
+    };
+    Rgb([channel(3.0), channel(2.0), channel(1.0)])
+}
+
+#[allow(dead_code, unused_imports)]
+mod tests {
+    use super::{plot_spectrum, heatmap_image, false_colour, WIDTH, HEIGHT, 
+                MARGIN, CUT, SPECTRUM};
+    use crate::metrics::Samples;
+    use nalgebra::DMatrix;
+
+    #[test]
+    fn test_heatmap_image() {
+        let a = DMatrix::from_fn(5, 4, |i, j| (i * 10 + j) as f64);
+        let mut b = a.clone();
+        b[(3, 1)] += 20.0;
+        b[(0, 2)] -= 5.0;
+        let original = Samples { channels: vec![a.clone(), a.clone()], peak: 255.0 };
+        let decoded = Samples { channels: vec![a, b], peak: 255.0 };
+
+        let (img, max) = heatmap_image(&original, &decoded);
+        assert_eq!(img.dimensions(), (5, 4));
+        assert_eq!(max, 20.0);
+        assert_eq!(*img.get_pixel(3, 1), false_colour(1.0));
+        assert_eq!(*img.get_pixel(0, 2), false_colour(0.25));
+        assert_eq!(*img.get_pixel(1, 1), false_colour(0.0));
+        assert_eq!(false_colour(0.0).0, [0, 0, 128]);
+        assert_eq!(false_colour(1.0).0, [128, 0, 0]);
+    }
+
+    #[test]
+    fn test_plot_spectrum() {
+        let path = std::env::temp_dir().join("svd-img-spectrum.png");
+        let path = path.to_str().unwrap();
+        let values = [100.0, 50.0, 10.0, 1.0, 0.1, 0.01];
+        let energy = values.iter().map(|s| s * s).sum();
+        let red_columns = |cut: usize| {
+            plot_spectrum(&values, cut, energy, path).unwrap();
+            let img = image::open(path).unwrap().into_rgb8();
+            assert_eq!(img.dimensions(), (WIDTH, HEIGHT));
+            (0..WIDTH).filter(|&x| {
+                (0..HEIGHT).any(|y| *img.get_pixel(x, y) == CUT)
+            }).collect::<Vec<u32>>()
+        };
+
+        // halfway between the last kept value and the first dropped one
+        let x = MARGIN + (WIDTH - 2 * MARGIN) * 5 / 10;
+        assert_eq!(red_columns(3), vec![x]);
+        let img = image::open(path).unwrap().into_rgb8();
+        assert!((MARGIN..HEIGHT - MARGIN).all(|y| *img.get_pixel(x, y) == CUT));
+        // the values go on a log scale from 10^-2 at the bottom to 10^2
+        let y_of = |log: f64| {
+            (HEIGHT - MARGIN) as f64 - (HEIGHT - 2 * MARGIN) as f64 * 
+                                       (log + 2.0) / 4.0
+        };
+        let x_of = |rank: u32| MARGIN + (WIDTH - 2 * MARGIN) * rank / 5;
+        assert_eq!(*img.get_pixel(x_of(1), y_of(50_f64.log10()).round() as u32),
+                   SPECTRUM);
+        assert_eq!(*img.get_pixel(x_of(3), y_of(0.0) as u32), SPECTRUM);
+        assert!(red_columns(0).is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,11 +1,37 @@
 use std::{
-    io::{Read, Error as IOError},
+    io::{Read, Seek, SeekFrom, Cursor, Error as IOError},
     fs::File,
     path::Path
 };
 
+/// Where a `FileReader` reads its bytes from.
+enum Source {
+    File(File),
+    Memory(Cursor<Vec<u8>>),
+}
+
+impl Read for Source {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IOError> {
+        match self {
+            Source::File(f) => f.read(buf),
+            Source::Memory(c) => c.read(buf),
+        }
+    }
+}
+
+impl Seek for Source {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, IOError> {
+        match self {
+            Source::File(f) => f.seek(pos),
+            Source::Memory(c) => c.seek(pos),
+        }
+    }
+}
+
 pub struct FileReader {
-    file: File,
+    file: Source,
+    bit_buffer: u32,
+    bit_count: u8,
 }
 
 #[derive(Debug)]
@@ -17,68 +43,111 @@ pub enum FileReaderError {
 impl FileReader {
     pub fn new(f: File) -> Self {
         FileReader {
-            file: f
+            file: Source::File(f),
+            bit_buffer: 0,
+            bit_count: 0,
+        }
+    }
+
+    /// Creates a reader over bytes already in memory.
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        FileReader {
+            file: Source::Memory(Cursor::new(bytes)),
+            bit_buffer: 0,
+            bit_count: 0,
         }
     }
 
     pub fn from_path(path: &Path) -> Result<Self, IOError> {
         let file = File::open(path)?;
-        Ok(Self {
-            file: file
-        })
+        Ok(Self::new(file))
     }
 
     pub fn from_name(name: &str) -> Result<Self, IOError> {
         Self::from_path(Path::new(name))
     }
 
+    /// Returns the number of bytes left between the current position and the
+    /// end of the file.
+    pub fn remaining(&mut self) -> Result<u64, IOError> {
+        let pos = self.file.seek(SeekFrom::Current(0))?;
+        let len = self.file.seek(SeekFrom::End(0))?;
+        self.file.seek(SeekFrom::Start(pos))?;
+        Ok(len.saturating_sub(pos))
+    }
+
+    /// Reads exactly `n` bytes.
+    pub fn read_bytes(&mut self, n: usize) -> Result<Vec<u8>, FileReaderError> {
+        let mut buffer = vec![0_u8; n];
+        self.file.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+
     pub fn read_u8(&mut self) -> Result<u8, FileReaderError> {
         let mut buffer = [0_u8; 1]; 
-        self.file.read(&mut buffer)?;
+        self.file.read_exact(&mut buffer)?;
         Ok(buffer[0])
     }
 
     pub fn read_u16(&mut self) -> Result<u16, FileReaderError> {
         let mut buffer = [0_u8; 2]; 
-        self.file.read(&mut buffer)?;
+        self.file.read_exact(&mut buffer)?;
         Ok(u16::from_be_bytes(buffer))
     }
 
     pub fn read_u32(&mut self) -> Result<u32, FileReaderError> {
         let mut buffer = [0_u8; 4]; 
-        self.file.read(&mut buffer)?;
+        self.file.read_exact(&mut buffer)?;
         Ok(u32::from_be_bytes(buffer))
     }
 
     pub fn read_i8(&mut self) -> Result<i8, FileReaderError> {
         let mut buffer = [0_u8; 1]; 
-        self.file.read(&mut buffer)?;
+        self.file.read_exact(&mut buffer)?;
         Ok(i8::from_be_bytes(buffer))
     }
 
     pub fn read_i16(&mut self) -> Result<i16, FileReaderError> {
         let mut buffer = [0_u8; 2]; 
-        self.file.read(&mut buffer)?;
+        self.file.read_exact(&mut buffer)?;
         Ok(i16::from_be_bytes(buffer))
     }
 
     pub fn read_i32(&mut self) -> Result<i32, FileReaderError> {
         let mut buffer = [0_u8; 4]; 
-        self.file.read(&mut buffer)?;
+        self.file.read_exact(&mut buffer)?;
         Ok(i32::from_be_bytes(buffer))
     }
 
     pub fn read_f32(&mut self) -> Result<f32, FileReaderError> {
         let mut buffer = [0_u8; 4]; 
-        self.file.read(&mut buffer)?;
+        self.file.read_exact(&mut buffer)?;
         Ok(f32::from_be_bytes(buffer))
     }
 
     pub fn read_f64(&mut self) -> Result<f64, FileReaderError> {
         let mut buffer = [0_u8; 8]; 
-        self.file.read(&mut buffer)?;
+        self.file.read_exact(&mut buffer)?;
         Ok(f64::from_be_bytes(buffer))
     }
+
+    /// Reads `n` bits (`n <= 16`) written with `FileWriter::write_bits`.
+    pub fn read_bits(&mut self, n: u8) -> Result<u32, FileReaderError> {
+        while self.bit_count < n {
+            self.bit_buffer = (self.bit_buffer << 8) | self.read_u8()? as u32;
+            self.bit_count += 8;
+        }
+        self.bit_count -= n;
+        let x = (self.bit_buffer >> self.bit_count) & ((1_u32 << n) - 1);
+        self.bit_buffer &= (1_u32 << self.bit_count) - 1;
+        Ok(x)
+    }
+
+    /// Drops the bits left in the last byte read by `read_bits`.
+    pub fn align_bits(&mut self) {
+        self.bit_buffer = 0;
+        self.bit_count = 0;
+    }
 }
 
 impl std::convert::From<IOError> for FileReaderError {
@@ -0,0 +1,163 @@
+//! Integer difference between the input matrices and the ones recomputed
+//! from the stored triplets, stored after the body of lossless files so that
+//! decoding gives back the exact input.
+//!
+//! Every value is zigzag mapped (0, -1, 1, -2, ... to 0, 1, 2, 3, ...) and
+//! written on as few bytes as needed, 7 bits per byte, the MSB being set on
+//! every byte but the last one. The bytes are then entropy coded.
+
+use super::{
+    Error,
+    read::FileReader,
+    write::FileWriter,
+    entropy,
+};
+
+use nalgebra::DMatrix;
+
+/// Largest number of bytes of one coded value.
+pub(crate) const MAX_VALUE_SIZE: usize = 5;
+
+/// `original - decoded`, for each matrix. The difference wraps around as
+/// aggregated pixels can use every bit of an `i32`.
+pub fn residual(original: &[DMatrix<i32>], decoded: &[DMatrix<i32>])
+    -> Vec<DMatrix<i32>> {
+    original.iter().zip(decoded).map(|(a, b)| {
+        a.zip_map(b, |x, y| x.wrapping_sub(y))
+    }).collect()
+}
+
+/// Adds `residual` to the `decoded` matrices, see `residual`.
+pub fn add_residual(decoded: &mut [DMatrix<i32>], residual: &[DMatrix<i32>]) {
+    for (m, r) in decoded.iter_mut().zip(residual) {
+        m.zip_apply(r, |x, y| x.wrapping_add(y));
+    }
+}
+
+/// Writes the residual matrices, row after row.
+pub fn write_residual(fw: &mut FileWriter, residual: &[DMatrix<i32>])
+    -> Result<(), Error> {
+
+    let mut bytes = Vec::new();
+    for m in residual {
+        for i in 0..m.nrows() {
+            for j in 0..m.ncols() {
+                push_value(&mut bytes, m[(i, j)]);
+            }
+        }
+    }
+    write_coded(fw, &bytes)
+}
+
+/// Reads what `write_residual` wrote for `n_planes` matrices of
+/// `height x width`.
+pub fn read_residual(fr: &mut FileReader, n_planes: usize, height: usize,
+                     width: usize) -> Result<Vec<DMatrix<i32>>, Error> {
+
+    let bytes = read_coded(fr, MAX_VALUE_SIZE * n_planes * height * width)?;
+
+    let mut pos = 0;
+    let mut res = Vec::with_capacity(n_planes);
+    for _p in 0..n_planes {
+        let mut m = DMatrix::<i32>::zeros(height, width);
+        for i in 0..height {
+            for j in 0..width {
+                m[(i, j)] = next_value(&bytes, &mut pos)?;
+            }
+        }
+        res.push(m);
+    }
+    Ok(res)
+}
+
+/// Writes the coded values `bytes`, entropy coded, after their size and the
+/// compressed one.
+pub(crate) fn write_coded(fw: &mut FileWriter, bytes: &[u8]) 
+    -> Result<(), Error> {
+    let compressed = entropy::compress(bytes, 1);
+    fw.write_u32(bytes.len() as u32)?;
+    fw.write_u32(compressed.len() as u32)?;
+    fw.write_all(&compressed)?;
+    Ok(())
+}
+
+/// Reads what `write_coded` wrote, that is at most `max_size` bytes.
+pub(crate) fn read_coded(fr: &mut FileReader, max_size: usize)
+    -> Result<Vec<u8>, Error> {
+    let size = fr.read_u32()? as usize;
+    let len = fr.read_u32()? as usize;
+    if size > max_size {
+        return Err(Error::InvalidHeader("invalid residual size"));
+    }
+    if len as u64 > fr.remaining()? {
+        return Err(Error::InvalidHeader("file is truncated"));
+    }
+    entropy::decompress(&fr.read_bytes(len)?, size, 1)
+}
+
+/// Codes `x` as its zigzag mapping, see `push_unsigned`.
+pub(crate) fn push_value(bytes: &mut Vec<u8>, x: i32) {
+    push_unsigned(bytes, ((x << 1) ^ (x >> 31)) as u32);
+}
+
+/// Codes `z` 7 bits at a time, the MSB being set on every byte but the last.
+pub(crate) fn push_unsigned(bytes: &mut Vec<u8>, mut z: u32) {
+    while z >= 0x80 {
+        bytes.push((z & 0x7f) as u8 | 0x80);
+        z >>= 7;
+    }
+    bytes.push(z as u8);
+}
+
+pub(crate) fn next_value(bytes: &[u8], pos: &mut usize) -> Result<i32, Error> {
+    let z = next_unsigned(bytes, pos)?;
+    Ok((z >> 1) as i32 ^ -((z & 1) as i32))
+}
+
+pub(crate) fn next_unsigned(bytes: &[u8], pos: &mut usize) 
+    -> Result<u32, Error> {
+    let mut z = 0_u32;
+    for k in 0..MAX_VALUE_SIZE {
+        let b = match bytes.get(*pos) {
+            Some(&b) => b,
+            None => return Err(Error::InvalidHeader("truncated residual"))
+        };
+        *pos += 1;
+        z |= ((b & 0x7f) as u32) << (7 * k);
+        if b & 0x80 == 0 {
+            return Ok(z);
+        }
+    }
+    Err(Error::InvalidHeader("invalid residual value"))
+}
+
+#[allow(dead_code, unused_imports)]
+mod tests {
+    use super::{push_value, next_value, write_residual, read_residual};
+    use crate::{read::FileReader, write::FileWriter};
+    use nalgebra::DMatrix;
+
+    #[test]
+    fn test_values() {
+        for &x in [0, -1, 1, 63, -64, 64, 1000, i32::MAX, i32::MIN].iter() {
+            let mut bytes = Vec::new();
+            push_value(&mut bytes, x);
+            assert!(bytes.len() <= 5);
+            let mut pos = 0;
+            assert_eq!(next_value(&bytes, &mut pos).unwrap(), x);
+            assert_eq!(pos, bytes.len());
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let residual = vec![
+            DMatrix::from_fn(5, 7, |i, j| (i as i32 - 2) * (j as i32 % 3)),
+            DMatrix::from_fn(5, 7, |i, j| if i == j { i32::MIN } else { 1 }),
+        ];
+        let mut fw = FileWriter::in_memory();
+        write_residual(&mut fw, &residual).unwrap();
+        let mut fr = FileReader::from_bytes(fw.into_bytes());
+        assert_eq!(read_residual(&mut fr, 2, 5, 7).unwrap(), residual);
+    }
+}
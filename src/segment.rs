@@ -0,0 +1,362 @@
+//! Sounds split in segments of a fixed number of frames, each one being
+//! decomposed on its own (with its own tiles and number of triplets) and
+//! stored after the previous one. Neither the encoder nor the decoder holds
+//! more than one segment: samples are read from the input WAV file and
+//! written to the output one as segments go.
+
+use super::{
+    Error,
+    read::FileReader,
+    write::FileWriter,
+    encode::{
+        Options,
+        CompressionPolicy,
+        Summary,
+        sound_options,
+        sound_planes,
+        matrices_from_sound_data,
+        write_tiled_approximation,
+        report_stored_quality,
+    },
+    decode::{
+        read_approximation,
+        sound_from_planes,
+        sound_from_float_planes,
+    },
+    header::FileHeader,
+    colour::ColourSpace,
+    residual::{residual, write_residual, read_residual, add_residual},
+    correction::{Correction, sample_range},
+    metrics::{Quality, sound_samples, float_samples},
+    svd::Convergence,
+};
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write, ErrorKind},
+    path::Path,
+};
+
+use nalgebra::DMatrix;
+
+use wav::{
+    BitDepth as WavData,
+    Header as WavHeader,
+};
+
+/// Encodes the sound in `input` to `output` in segments of
+/// `options.segment_length` frames.
+pub fn encode_segments(input: &str, output: &str, options: &mut Options)
+    -> Result<Summary, Error> {
+
+    options.is_wav |= input.ends_with(".WAV") || input.ends_with(".wav");
+    if !options.is_wav {
+        return Err(Error::UnsupportedMode("segments of an image"));
+    }
+    if let CompressionPolicy::Size(_) = options.policy {
+        return Err(Error::UnsupportedMode("maximum size of a segmented \
+                                           sound"));
+    }
+
+    let mut reader = SoundReader::open(input)?;
+    let header = reader.header;
+    let channels = sound_options(&header, reader.n_samples as usize, 
+                                 options)?;
+    let float = header.bits_per_sample == 32;
+    if float && (options.lossless || options.tolerance.is_some()) {
+        return Err(Error::UnsupportedMode("lossless float samples"));
+    }
+
+    let mut fw = FileWriter::from_name(output)?;
+    let wav = Some((header, reader.n_samples));
+    let file_header = FileHeader::from_options(options, wav);
+    file_header.write(&mut fw)?;
+
+    let per_segment = options.segment_length.unwrap_or(1) * channels;
+    let n_segments = file_header.n_segments();
+    let mut parts = Vec::with_capacity(n_segments);
+    let mut convergence = Convergence { converged: true, residuals: vec![] };
+    for s in 0..n_segments {
+        println!("Segment {} of {}", s + 1, n_segments);
+        let data = reader.read_samples(per_segment)?;
+        let n = reader.last_read;
+        options.original_file_size =
+            (n * header.bits_per_sample as usize / 8) as u64;
+        let h = (header, n as u32);
+        let original = sound_samples(&data, header);
+
+        let (decoded, c) = if let WavData::ThirtyTwoFloat(d) = &data {
+            let planes: Vec<DMatrix<f32>> =
+                matrices_from_sound_data(d, channels, options.layout);
+            let (recomputed, c) =
+                write_tiled_approximation(&mut fw, &file_header, &planes,
+                                          options)?;
+            (float_samples(&recomputed, h, options.layout), c)
+        } else {
+            let mut planes = sound_planes(&data, channels, options.layout)
+                .ok_or(Error::UnsupportedMode("WAV bit depth"))?;
+            options.colour_space.from_rgb(&mut planes);
+            let (mut recomputed, c) =
+                write_tiled_approximation(&mut fw, &file_header, &planes,
+                                          options)?;
+            options.colour_space.to_rgb(&mut recomputed);
+            let mut decoded =
+                sound_samples(&sound_from_planes(&recomputed, h,
+                                                 options.layout), header);
+            if file_header.lossless {
+                options.colour_space.to_rgb(&mut planes);
+                write_residual(&mut fw, &residual(&planes, &recomputed))?;
+            }
+            if let Some(t) = file_header.tolerance {
+                let correction = Correction::between(&original, &decoded, t);
+                println!("{} samples corrected to be within {} of the input",
+                         correction.n_corrected(), t);
+                correction.write(&mut fw)?;
+                let (lo, hi) = sample_range(Some(header.bits_per_sample));
+                correction.apply_to_samples(&mut decoded, lo, hi);
+            }
+            (decoded, c)
+        };
+
+        convergence.converged &= c.converged;
+        convergence.residuals.extend(c.residuals);
+        parts.push((Quality::between(&original, &decoded), n));
+    }
+
+    let quality = Quality::merge(&parts, options.peak());
+    report_stored_quality(&quality, &file_header);
+    Ok(Summary { convergence, quality })
+}
+
+/// Decodes the segmented sound of `file_header` from `fr` (right after the
+/// header) to `output`, one segment at a time.
+pub fn decode_segments(fr: &mut FileReader, file_header: &FileHeader,
+                       output: &str, options: &Options) -> Result<(), Error> {
+
+    let (header, n_samples) = match file_header.wav {
+        Some(h) => h,
+        None => return Err(Error::InvalidHeader("missing WAV header")),
+    };
+    let channels = (header.channel_count as usize).max(1);
+    let per_segment = file_header.segment_length.unwrap_or(1) as usize
+                      * channels;
+    let layout = file_header.layout();
+    let space = ColourSpace::from_id(file_header.colour_space)
+        .unwrap_or(ColourSpace::Rgb);
+    // the residual and the correction only complete the whole approximation
+    let complete = options.rank.is_none();
+
+    let mut writer = SoundWriter::create(output, header, n_samples)?;
+    let mut left = n_samples as usize;
+    while left > 0 {
+        let n = per_segment.min(left);
+        left -= n;
+        let h = (header, n as u32);
+
+        if file_header.float_samples() {
+            let planes: Vec<DMatrix<f32>> =
+                read_approximation(fr, file_header, options)?;
+            let data = sound_from_float_planes(&planes, h, layout);
+            writer.write_samples(&data)?;
+            continue;
+        }
+
+        let mut planes: Vec<DMatrix<i32>> =
+            read_approximation(fr, file_header, options)?;
+        if file_header.planar {
+            space.to_rgb(&mut planes);
+        }
+        if file_header.lossless {
+            let (height, width) = planes[0].shape();
+            let residual = read_residual(fr, planes.len(), height, width)?;
+            if complete {
+                add_residual(&mut planes, &residual);
+            }
+        }
+        let mut data = sound_from_planes(&planes, h, layout);
+        if let Some(t) = file_header.tolerance {
+            let correction = Correction::read(fr, t, 
+                                              file_header.sparse_correction(),
+                                              1, 1, n)?;
+            if complete {
+                correction.apply_to_sound(&mut data);
+            }
+        }
+        writer.write_samples(&data)?;
+    }
+    writer.finish()
+}
+
+fn invalid_data(message: &'static str) -> Error {
+    io::Error::new(ErrorKind::InvalidData, message).into()
+}
+
+/// Reads the samples of a WAV file a few at a time.
+pub struct SoundReader {
+    reader: BufReader<File>,
+    pub header: WavHeader,
+    /// Number of samples (of all the channels) in the file.
+    pub n_samples: u32,
+    /// Number of samples not read yet.
+    left: usize,
+    /// Number of samples returned by the last `read_samples`.
+    pub last_read: usize,
+}
+
+impl SoundReader {
+    /// Opens the WAV file `input` and reads its header, stopping at the
+    /// start of its samples.
+    pub fn open(input: &str) -> Result<Self, Error> {
+        let mut reader = BufReader::new(File::open(Path::new(input))?);
+        let mut tag = [0_u8; 12];
+        reader.read_exact(&mut tag)?;
+        if &tag[0..4] != b"RIFF" || &tag[8..12] != b"WAVE" {
+            return Err(invalid_data("not a WAV file"));
+        }
+
+        let mut header = None;
+        loop {
+            let mut chunk = [0_u8; 8];
+            reader.read_exact(&mut chunk)?;
+            let size = u32::from_le_bytes([chunk[4], chunk[5],
+                                           chunk[6], chunk[7]]) as usize;
+            if &chunk[0..4] == b"data" {
+                let h: WavHeader = header.ok_or_else(|| {
+                    invalid_data("no WAV header before the samples")
+                })?;
+                let n = size / (h.bits_per_sample as usize / 8).max(1);
+                return Ok(SoundReader { reader, header: h, n_samples: n as u32,
+                                        left: n, last_read: 0 });
+            }
+
+            // chunks are aligned on 2 bytes
+            let mut bytes = vec![0_u8; size + size % 2];
+            reader.read_exact(&mut bytes)?;
+            if &chunk[0..4] == b"fmt " && size >= 16 {
+                let mut raw = [0_u8; 16];
+                raw.copy_from_slice(&bytes[..16]);
+                header = Some(raw.into());
+            }
+        }
+    }
+
+    /// Reads the next `n` samples (fewer at the end of the file).
+    pub fn read_samples(&mut self, n: usize) -> Result<WavData, Error> {
+        let n = n.min(self.left);
+        let size = (self.header.bits_per_sample as usize / 8).max(1);
+        let mut bytes = vec![0_u8; n * size];
+        self.reader.read_exact(&mut bytes)?;
+        self.left -= n;
+        self.last_read = n;
+
+        let samples = bytes.chunks_exact(size);
+        Ok(match (self.header.bits_per_sample, self.header.audio_format) {
+            (8, _) => WavData::Eight(bytes),
+            (16, _) => WavData::Sixteen(samples.map(|b| {
+                i16::from_le_bytes([b[0], b[1]])
+            }).collect()),
+            // in the 3 upper bytes, as `wav` does
+            (24, _) => WavData::TwentyFour(samples.map(|b| {
+                i32::from_le_bytes([0, b[0], b[1], b[2]])
+            }).collect()),
+            (32, 3) => WavData::ThirtyTwoFloat(samples.map(|b| {
+                f32::from_le_bytes([b[0], b[1], b[2], b[3]])
+            }).collect()),
+            _ => return Err(Error::UnsupportedMode("WAV bit depth")),
+        })
+    }
+}
+
+/// Writes a WAV file a few samples at a time, its header being written
+/// first.
+pub struct SoundWriter {
+    writer: BufWriter<File>,
+    /// Number of bytes of samples announced by the header.
+    size: usize,
+}
+
+impl SoundWriter {
+    /// Creates the WAV file `output` holding `n_samples` samples.
+    pub fn create(output: &str, header: WavHeader, n_samples: u32)
+        -> Result<Self, Error> {
+
+        let mut writer = BufWriter::new(File::create(Path::new(output))?);
+        let size = n_samples as usize * header.bits_per_sample as usize / 8;
+        let fmt: [u8; 16] = header.into();
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&((4 + 8 + 16 + 8 + size + size % 2) as u32)
+                                .to_le_bytes())?;
+        writer.write_all(b"WAVEfmt ")?;
+        writer.write_all(&16_u32.to_le_bytes())?;
+        writer.write_all(&fmt)?;
+        writer.write_all(b"data")?;
+        writer.write_all(&(size as u32).to_le_bytes())?;
+        Ok(SoundWriter { writer, size })
+    }
+
+    pub fn write_samples(&mut self, data: &WavData) -> Result<(), Error> {
+        match data {
+            WavData::Eight(v) => self.writer.write_all(v)?,
+            WavData::Sixteen(v) => for x in v {
+                self.writer.write_all(&x.to_le_bytes())?;
+            },
+            WavData::TwentyFour(v) => for x in v {
+                self.writer.write_all(&x.to_le_bytes()[1..])?;
+            },
+            WavData::ThirtyTwoFloat(v) => for x in v {
+                self.writer.write_all(&x.to_le_bytes())?;
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Pads the samples to an even number of bytes and flushes the file.
+    pub fn finish(mut self) -> Result<(), Error> {
+        if self.size % 2 != 0 {
+            self.writer.write_all(&[0])?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[allow(dead_code, unused_imports)]
+mod tests {
+    use super::{SoundReader, SoundWriter};
+    use wav::{BitDepth as WavData, Header as WavHeader};
+
+    #[test]
+    fn test_sound_stream() {
+        let path = std::env::temp_dir().join("svd-img-segment-test.wav");
+        let name = path.to_str().unwrap();
+        let samples: Vec<i32> = (0..1001).map(|k| (k * 7919 % 65536 - 32768) << 8)
+                                         .collect();
+        let header = WavHeader::new(1, 1, 8000, 24);
+
+        let mut writer = SoundWriter::create(name, header, 1001).unwrap();
+        for part in samples.chunks(300) {
+            writer.write_samples(&WavData::TwentyFour(part.to_vec())).unwrap();
+        }
+        writer.finish().unwrap();
+
+        // the file is the one `wav` writes
+        let mut file = std::fs::File::open(&path).unwrap();
+        match wav::read(&mut file).unwrap().1 {
+            WavData::TwentyFour(v) => assert_eq!(v, samples),
+            _ => panic!("wrong bit depth"),
+        }
+
+        let mut reader = SoundReader::open(name).unwrap();
+        assert_eq!(reader.n_samples, 1001);
+        let mut read = Vec::new();
+        while read.len() < samples.len() {
+            match reader.read_samples(400).unwrap() {
+                WavData::TwentyFour(v) => read.extend(v),
+                _ => panic!("wrong bit depth"),
+            }
+        }
+        assert_eq!(read, samples);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,320 @@
+use super::{
+    Error,
+    encode::SVDVectors,
+};
+
+use nalgebra::{ DMatrix, DVector, RealField };
+
+use std::cmp::Ordering;
+
+/// How the singular triplets kept in a file are computed.
+pub enum SVDMethod {
+    /// Full decomposition of the matrix, only the largest triplets are kept.
+    Exact,
+    /// Randomized range finder (Halko, Martinsson & Tropp, 2011): the matrix
+    /// is projected on `k + oversampling` random directions, refined by
+    /// `power_iterations`, and only that small projection is decomposed.
+    Randomized { oversampling: usize, power_iterations: usize },
+}
+
+const DEFAULT_OVERSAMPLING: usize = 10;
+const DEFAULT_POWER_ITERATIONS: usize = 2;
+
+impl SVDMethod {
+    pub fn randomized() -> Self {
+        SVDMethod::Randomized {
+            oversampling: DEFAULT_OVERSAMPLING,
+            power_iterations: DEFAULT_POWER_ITERATIONS
+        }
+    }
+}
+
+/// How well the kept triplets are converged.
+pub struct Convergence {
+    /// Whether the decomposition finished within the iteration cap.
+    pub converged: bool,
+    /// For each kept triplet, `max(|m v_i - sv_i u_i|, |m^T u_i - sv_i v_i|)`
+    /// relative to the largest singular value (0 for the triplets of a full
+    /// decomposition that converged).
+    pub residuals: Vec<f64>,
+}
+
+impl Convergence {
+    pub fn max_residual(&self) -> f64 {
+        self.residuals.iter().cloned().fold(0.0, f64::max)
+    }
+}
+
+/// Seed of the random directions, fixed so that encoding is reproducible.
+const SEED: u64 = 0x5eed_5eed_5eed_5eed;
+
+/// Returns the `k` singular triplets of `m` with the largest singular
+/// values, sorted by decreasing singular value.
+///
+/// The exact decomposition is stopped after `max_niter` iterations (0 for no
+/// limit). If it did not converge by then, the triplets are computed by
+/// `max_niter` steps of orthogonal iteration (see `subspace_svd`) instead,
+/// and the result is flagged as not converged, its residuals telling how far
+/// each triplet is. The randomized method does a fixed number of iterations
+/// and ignores `max_niter`.
+pub fn truncated_svd<N>(m: DMatrix<N>, k: usize, method: &SVDMethod, eps: N,
+                        max_niter: usize) 
+    -> Result<(SVDVectors<N>, Convergence), Error>
+    where N: RealField
+    {
+    match method {
+        SVDMethod::Exact => {
+            // only needed again if the decomposition can stop early
+            let copy = if max_niter > 0 { Some(m.clone()) } else { None };
+            match (exact_svd(m, k, eps, max_niter)?, copy) {
+                (Some(vectors), _) => {
+                    let residuals = vec![0.0; vectors.len()];
+                    Ok((vectors, Convergence { converged: true, residuals }))
+                },
+                (None, Some(m)) => {
+                    let vectors = subspace_svd(&m, k, eps, max_niter)?;
+                    let residuals = residuals(&m, &vectors);
+                    Ok((vectors, Convergence { converged: false, residuals }))
+                },
+                (None, None) => Err(Error::SVDError)
+            }
+        },
+        SVDMethod::Randomized { oversampling, power_iterations } => {
+            let vectors = randomized_svd(&m, k, *oversampling, 
+                                         *power_iterations, eps)?;
+            let residuals = residuals(&m, &vectors);
+            Ok((vectors, Convergence { converged: true, residuals }))
+        }
+    }
+}
+
+/// See `Convergence::residuals`.
+fn residuals<N>(m: &DMatrix<N>, vectors: &SVDVectors<N>) -> Vec<f64>
+    where N: RealField
+    {
+    let sv_max = match vectors.first() {
+        Some(t) => nalgebra::try_convert::<N, f64>(t.0).unwrap_or(0.0),
+        None => return Vec::new()
+    };
+
+    vectors.iter().map(|(sv, u, v)| {
+        let r_u = (m * v - u * *sv).norm();
+        let r_v = (m.tr_mul(u) - v * *sv).norm();
+        let r = nalgebra::try_convert::<N, f64>(r_u.max(r_v)).unwrap_or(0.0);
+        if sv_max > 0.0 { r / sv_max } else { r }
+    }).collect()
+}
+
+/// The `k` largest triplets of the full decomposition of `m`, `None` if it
+/// did not converge within `max_niter` iterations.
+fn exact_svd<N>(m: DMatrix<N>, k: usize, eps: N, max_niter: usize)
+    -> Result<Option<SVDVectors<N>>, Error>
+    where N: RealField
+    {
+    let svd = match m.try_svd(true, true, eps, max_niter) {
+        Some(x) => x,
+        None => return Ok(None)
+    };
+
+    let u = match svd.u {
+        Some(u) => u,
+        None => return Err(Error::NoSVDResult)
+    };
+
+    let v_t = match svd.v_t {
+        Some(v_t) => v_t,
+        None => return Err(Error::NoSVDResult)
+    };
+
+    Ok(Some(sorted_triplets(&u, &svd.singular_values, &v_t, k)))
+}
+
+fn randomized_svd<N>(m: &DMatrix<N>, k: usize, oversampling: usize,
+                     power_iterations: usize, eps: N)
+    -> Result<SVDVectors<N>, Error>
+    where N: RealField
+    {
+    let mut q = random_range(m, k + oversampling);
+    for _i in 0..power_iterations {
+        q = power_iteration(m, &q);
+    }
+    projected_triplets(m, &q, k, eps)
+}
+
+/// Orthogonal iteration: the basis given by `random_range` is refined by
+/// `niter - 1` calls to `power_iteration`, and the triplets of `m` projected
+/// on it are returned.
+fn subspace_svd<N>(m: &DMatrix<N>, k: usize, eps: N, niter: usize)
+    -> Result<SVDVectors<N>, Error>
+    where N: RealField
+    {
+    let mut q = random_range(m, k + DEFAULT_OVERSAMPLING);
+    for _i in 1..niter {
+        q = power_iteration(m, &q);
+    }
+    projected_triplets(m, &q, k, eps)
+}
+
+/// Orthonormal basis of the range of `m * omega`, `omega` being `l` random
+/// directions (fewer if `m` is smaller), that approximates the range of `m`.
+fn random_range<N>(m: &DMatrix<N>, l: usize) -> DMatrix<N>
+    where N: RealField
+    {
+    let (h, w) = m.shape();
+    let l = l.min(h).min(w);
+
+    let mut rng = Rng::new(SEED);
+    let omega = DMatrix::<N>::from_fn(w, l, |_, _| {
+        nalgebra::convert(rng.gaussian())
+    });
+    (m * omega).qr().q()
+}
+
+/// Brings the basis `q` closer to the range of the largest singular
+/// vectors of `m`.
+fn power_iteration<N>(m: &DMatrix<N>, q: &DMatrix<N>) -> DMatrix<N>
+    where N: RealField
+    {
+    let z = m.tr_mul(q).qr().q();
+    (m * z).qr().q()
+}
+
+/// The `k` largest triplets of `m` projected on the orthonormal basis `q`.
+fn projected_triplets<N>(m: &DMatrix<N>, q: &DMatrix<N>, k: usize, eps: N)
+    -> Result<SVDVectors<N>, Error>
+    where N: RealField
+    {
+    // small enough to be decomposed without iteration limit
+    let b = q.tr_mul(m);
+    let mut vectors = exact_svd(b, k, eps, 0)?.ok_or(Error::SVDError)?;
+    for triplet in vectors.iter_mut() {
+        triplet.1 = q * &triplet.1;
+    }
+    Ok(vectors)
+}
+
+/// Extracts the `k` triplets with the largest singular values.
+fn sorted_triplets<N>(u: &DMatrix<N>, sv: &DVector<N>, v_t: &DMatrix<N>,
+                      k: usize) -> SVDVectors<N>
+    where N: RealField
+    {
+    let mut order: Vec<usize> = (0..sv.len()).collect();
+    order.sort_by(|&a, &b| sv[b].partial_cmp(&sv[a]).unwrap_or(Ordering::Equal));
+
+    order.iter().take(k).map(|&i| {
+        (sv[i], u.column(i).into_owned(), v_t.row(i).transpose())
+    }).collect()
+}
+
+/// xorshift64* generator, good enough to draw random directions.
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in `]0, 1]`.
+    fn uniform(&mut self) -> f64 {
+        ((self.next_u64() >> 11) + 1) as f64 / (1_u64 << 53) as f64
+    }
+
+    /// Standard normal distribution, Box-Muller transform.
+    fn gaussian(&mut self) -> f64 {
+        let u1 = self.uniform();
+        let u2 = self.uniform();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+}
+
+#[allow(dead_code, unused_imports)]
+mod tests {
+    use super::{truncated_svd, SVDMethod, Rng};
+    use nalgebra::DMatrix;
+
+    /// Matrix with quickly decreasing singular values, plus some noise.
+    fn test_matrix(h: usize, w: usize) -> DMatrix<f64> {
+        let mut rng = Rng::new(42);
+        DMatrix::from_fn(h, w, |i, j| {
+            let (x, y) = (i as f64 / h as f64, j as f64 / w as f64);
+            100.0 * (3.0 * x + y).sin() + 40.0 * (x * y * 7.0).cos()
+                + 10.0 * (5.0 * x - 2.0 * y).sin() * y + rng.gaussian()
+        })
+    }
+
+    fn approximation_error(m: &DMatrix<f64>, k: usize, method: &SVDMethod)
+        -> (Vec<f64>, f64) {
+        let (vectors, _) = truncated_svd(m.clone(), k, method, 1.0e-10, 0)
+            .unwrap();
+        let mut r = m.clone();
+        for (sv, u, v_t) in vectors.iter() {
+            r -= u * v_t.transpose() * *sv;
+        }
+        (vectors.iter().map(|t| t.0).collect(), r.norm())
+    }
+
+    #[test]
+    fn test_sorted() {
+        let m = test_matrix(30, 20);
+        let (sv, _) = approximation_error(&m, 20, &SVDMethod::Exact);
+        for i in 1..sv.len() {
+            assert!(sv[i - 1] >= sv[i]);
+        }
+    }
+
+    #[test]
+    fn test_randomized_accuracy() {
+        let m = test_matrix(120, 90);
+        for &k in [1_usize, 3, 8].iter() {
+            let (sv_e, err_e) = approximation_error(&m, k, &SVDMethod::Exact);
+            let (sv_r, err_r) = approximation_error(&m, k,
+                                                    &SVDMethod::randomized());
+            assert_eq!(sv_r.len(), k);
+            for i in 0..k {
+                assert!((sv_e[i] - sv_r[i]).abs() <= 1.0e-3 * sv_e[0]);
+            }
+            assert!(err_r <= 1.01 * err_e);
+        }
+    }
+
+    #[test]
+    fn test_iteration_cap() {
+        let m = test_matrix(60, 40);
+        let (_, c) = truncated_svd(m.clone(), 5, &SVDMethod::Exact, 1.0e-10, 0)
+            .unwrap();
+        assert!(c.converged);
+        assert_eq!(c.max_residual(), 0.0);
+
+        let (vectors, c) = truncated_svd(m.clone(), 5, &SVDMethod::Exact, 
+                                         1.0e-10, 1).unwrap();
+        assert!(!c.converged);
+        assert_eq!(vectors.len(), 5);
+        assert_eq!(c.residuals.len(), 5);
+        assert!(c.max_residual() > 0.0);
+
+        // more iterations only bring the triplets closer
+        let (_, c2) = truncated_svd(m.clone(), 5, &SVDMethod::Exact, 1.0e-10,
+                                    3).unwrap();
+        assert!(c2.max_residual() < c.max_residual());
+
+        // a cap that is not reached changes nothing
+        let (vectors, c3) = truncated_svd(m.clone(), 5, &SVDMethod::Exact,
+                                          1.0e-10, 1000).unwrap();
+        let (expected, _) = truncated_svd(m, 5, &SVDMethod::Exact, 1.0e-10, 0)
+            .unwrap();
+        assert!(c3.converged);
+        assert_eq!(c3.max_residual(), 0.0);
+        for (t, e) in vectors.iter().zip(expected.iter()) {
+            assert_eq!(t.0, e.0);
+        }
+    }
+}
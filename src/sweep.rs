@@ -0,0 +1,203 @@
+//! Rate-distortion report: size of the file and distortion of the decoded
+//! result for every number of kept triplets, from a single decomposition.
+
+use super::{
+    Error,
+    read::FileReader,
+    encode::{
+        Options,
+        CompressionPolicy,
+        read_matrix,
+        matrix_reduce_f64,
+        matrix_reduce_f32,
+        write_vectors_f64,
+        write_vectors_f32,
+        serialize,
+        payload_size,
+    },
+    decode::{read_vectors_f64, read_vectors_f32, to_f64},
+    header::FileHeader,
+    metrics::{Quality, input_samples, decoded_samples},
+};
+
+use std::{
+    fs::File,
+    io::Write,
+    path::Path,
+};
+
+use nalgebra::DMatrix;
+
+pub enum ReportFormat {
+    Csv,
+    Json
+}
+
+/// Size and distortion of the file keeping `rank` triplets.
+pub struct SweepPoint {
+    pub rank: usize,
+    /// Exact size of the compressed file.
+    pub bytes: usize,
+    pub quality: Quality,
+}
+
+/// Writes in `output` the report of `rank_sweep` in the given format.
+pub fn sweep(input: &str, output: &str, options: &mut Options,
+             format: ReportFormat) -> Result<(), Error> {
+
+    let points = rank_sweep(input, options)?;
+
+    let mut out = File::create(Path::new(output))?;
+    match format {
+        ReportFormat::Csv => {
+            writeln!(out, "rank,bytes,mse,psnr,max_error,ssim")?;
+            for p in points.iter() {
+                writeln!(out, "{},{},{},{},{},{}", p.rank, p.bytes, 
+                         p.quality.mse, p.quality.psnr, p.quality.max_error, 
+                         p.quality.ssim)?;
+            }
+        },
+        ReportFormat::Json => {
+            writeln!(out, "[")?;
+            for (i, p) in points.iter().enumerate() {
+                writeln!(out, "  {{\"rank\": {}, \"bytes\": {}, \"mse\": {}, \
+                                \"psnr\": {}, \"max_error\": {}, \
+                                \"ssim\": {}}}{}",
+                         p.rank, p.bytes, p.quality.mse, json_number(p.quality.psnr),
+                         p.quality.max_error, p.quality.ssim, 
+                         if i + 1 < points.len() { "," } else { "" })?;
+            }
+            writeln!(out, "]")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Size and distortion of the file encoding `input` with `options`, for
+/// every number of triplets up to the one asked with `-n` (all of them
+/// otherwise). The decomposition is computed once and the approximations
+/// are built by adding one triplet at a time, as decoded from the file (that
+/// is, after quantization).
+pub fn rank_sweep(input: &str, options: &mut Options)
+    -> Result<Vec<SweepPoint>, Error> {
+
+    let (planes, wav) = read_matrix(input, options)?;
+    if planes.len() != 1 || options.tile_size.is_some() {
+        return Err(Error::UnsupportedMode("rank sweep of tiles or planes"));
+    }
+    let matrix = &planes[0];
+    let (h, w) = matrix.shape();
+    let original = input_samples(input, options)?;
+
+    let max_rank = match options.policy {
+        CompressionPolicy::Number(n) => n.min(h.min(w)),
+        _ => h.min(w)
+    };
+    options.policy = CompressionPolicy::with_number(max_rank);
+    let header = FileHeader::from_options(options, wav);
+
+    let (bytes, vectors) = if options.use_f64 {
+        let (vectors, _) = matrix_reduce_f64(matrix, options)?;
+        let bytes = serialize(&vectors, &header, write_vectors_f64)?;
+        let mut fr = FileReader::from_bytes(bytes.clone());
+        (bytes, read_vectors_f64(&mut fr, &header, vectors.len(), h, w)?)
+    } else {
+        let (vectors, _) = matrix_reduce_f32(matrix, options)?;
+        let bytes = serialize(&vectors, &header, write_vectors_f32)?;
+        let mut fr = FileReader::from_bytes(bytes.clone());
+        let stored = read_vectors_f32(&mut fr, &header, vectors.len(), h, w)?;
+        (bytes, to_f64(&stored))
+    };
+
+    let triplet_size = header.triplet_size(h, w);
+    let mut m = DMatrix::<f64>::zeros(h, w);
+    let mut points = Vec::with_capacity(vectors.len());
+    for (i, (sv, u, v)) in vectors.iter().enumerate() {
+        m.ger(*sv, u, v, 1.0);
+        let recomputed = m.map(|x| x.round() as i32);
+        let decoded = decoded_samples(&[recomputed], options, wav)?;
+
+        let n = i + 1;
+        points.push(SweepPoint {
+            rank: n,
+            bytes: header.overhead(None) +
+                   payload_size(&bytes[..n * triplet_size], &header),
+            quality: Quality::between(&original, &decoded),
+        });
+    }
+
+    Ok(points)
+}
+
+/// JSON has no infinite numbers.
+pub(crate) fn json_number(x: f64) -> String {
+    if x.is_finite() { x.to_string() } else { "null".to_string() }
+}
+
+#[allow(dead_code, unused_imports)]
+mod tests {
+    use super::rank_sweep;
+    use crate::{
+        Error,
+        encode::{encode, Options, CompressionPolicy},
+    };
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn test_rank_sweep() {
+        let temp = |name: &str| {
+            std::env::temp_dir().join(name).to_str().unwrap().to_string()
+        };
+        let (input, output) = (temp("svd-img-sweep.png"), 
+                               temp("svd-img-sweep.svd"));
+        RgbImage::from_fn(24, 20, |i, j| {
+            let (x, y) = (i as f64, j as f64);
+            let value = |c: f64| {
+                (128.0 + 60.0 * (x / (5.0 + c)).sin() * (y / 7.0).cos() 
+                       + 30.0 * (x * y / (40.0 + 10.0 * c)).sin()) as u8
+            };
+            Rgb([value(0.0), value(1.0), value(2.0)])
+        }).save(&input).unwrap();
+
+        let storages: [fn(&mut Options); 3] = [
+            |_| {},
+            |o| o.use_f64 = false,
+            |o| { o.quantization = Some(8); o.entropy_coding = true; },
+        ];
+        for set in storages.iter() {
+            let mut options = Options::default();
+            options.aggregator = None;
+            set(&mut options);
+            options.policy = CompressionPolicy::with_number(12);
+            let points = rank_sweep(&input, &mut options).unwrap();
+            assert_eq!(points.len(), 12);
+            assert!(points.iter().enumerate().all(|(i, p)| p.rank == i + 1));
+            for p in points.windows(2) {
+                assert!(p[1].quality.mse <= p[0].quality.mse);
+            }
+
+            // the sizes are the ones of the encoded files
+            for &n in [1_usize, 5, 12].iter() {
+                let mut options = Options::default();
+                options.aggregator = None;
+                set(&mut options);
+                options.policy = CompressionPolicy::with_number(n);
+                encode(&input, &output, &mut options).unwrap();
+                let size = std::fs::metadata(&output).unwrap().len();
+                assert_eq!(points[n - 1].bytes as u64, size);
+            }
+        }
+
+        let mut options = Options::default();
+        options.tile_size = Some(8);
+        match rank_sweep(&input, &mut options) {
+            Err(e) => assert_eq!(format!("{:?}", e), format!("{:?}", 
+                Error::UnsupportedMode("rank sweep of tiles or planes"))),
+            Ok(_) => panic!("rank sweep of tiles"),
+        }
+        for file in [input, output].iter() {
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+}
@@ -0,0 +1,550 @@
+use super::{
+    Error,
+    read::FileReader,
+    write::FileWriter,
+    encode::{Options, CompressionPolicy, Sample, SVDVectors, WriteVectors, 
+             write_payload, energy_of, report_energy, report_convergence,
+             serialize, payload_size, largest_fitting, singular_values, 
+             plot_if_asked},
+    decode::{read_payload, ReadVectors},
+    header::FileHeader,
+    svd::{truncated_svd, Convergence},
+};
+
+use nalgebra::{ DMatrix, RealField, Scalar };
+
+use std::cmp::Ordering;
+
+/// Splitting of `planes` matrices of `height x width` in blocks of
+/// `tile_height x tile_width`, the last row and column of tiles being
+/// smaller if needed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tiling {
+    pub height: usize,
+    pub width: usize,
+    pub tile_height: usize,
+    pub tile_width: usize,
+    /// Number of matrices (one per channel in planar mode) split the same
+    /// way, their tiles being stored one matrix after the other.
+    pub planes: usize,
+}
+
+impl Tiling {
+    pub fn new(height: usize, width: usize, tile_height: usize,
+               tile_width: usize) -> Self {
+        Tiling {
+            height, width,
+            tile_height: tile_height.min(height),
+            tile_width: tile_width.min(width),
+            planes: 1,
+        }
+    }
+
+    pub fn with_planes(mut self, planes: usize) -> Self {
+        self.planes = planes;
+        self
+    }
+
+    pub fn n_rows(&self) -> usize {
+        (self.height + self.tile_height - 1) / self.tile_height
+    }
+
+    pub fn n_cols(&self) -> usize {
+        (self.width + self.tile_width - 1) / self.tile_width
+    }
+
+    pub fn n_tiles(&self) -> usize {
+        self.n_rows() * self.n_cols()
+    }
+
+    /// Number of tiles of all the planes.
+    pub fn n_blocks(&self) -> usize {
+        self.n_tiles() * self.planes
+    }
+
+    /// Size `(height, width)` of every tile of every plane, in the order they
+    /// are stored.
+    pub fn blocks(&self) -> Vec<(usize, usize)> {
+        let tiles = self.tiles();
+        let mut res = Vec::with_capacity(self.n_blocks());
+        for _p in 0..self.planes {
+            res.extend(tiles.iter().map(|&(_, _, h, w)| (h, w)));
+        }
+        res
+    }
+
+    /// Position and size `(row, col, height, width)` of every tile, row
+    /// after row.
+    pub fn tiles(&self) -> Vec<(usize, usize, usize, usize)> {
+        let mut res = Vec::with_capacity(self.n_tiles());
+        for i in 0..self.n_rows() {
+            for j in 0..self.n_cols() {
+                let row = i * self.tile_height;
+                let col = j * self.tile_width;
+                res.push((row, col,
+                          self.tile_height.min(self.height - row),
+                          self.tile_width.min(self.width - col)));
+            }
+        }
+        res
+    }
+
+    pub fn split<T: Scalar>(&self, matrix: &DMatrix<T>) -> Vec<DMatrix<T>> {
+        self.tiles().iter().map(|&(row, col, h, w)| {
+            matrix.slice((row, col), (h, w)).into_owned()
+        }).collect()
+    }
+
+    /// Splits every plane, the tiles of the first plane coming first.
+    pub fn split_planes<T: Scalar>(&self, planes: &[DMatrix<T>])
+        -> Vec<DMatrix<T>> {
+        planes.iter().flat_map(|m| self.split(m)).collect()
+    }
+
+    pub fn assemble<T: Sample>(&self, tiles: &[DMatrix<T>]) -> DMatrix<T> {
+        let mut m = DMatrix::from_element(self.height, self.width, T::ZERO);
+        for (&(row, col, h, w), tile) in self.tiles().iter().zip(tiles) {
+            m.slice_mut((row, col), (h, w)).copy_from(tile);
+        }
+        m
+    }
+
+    /// Inverse of `split_planes`.
+    pub fn assemble_planes<T: Sample>(&self, tiles: &[DMatrix<T>]) 
+        -> Vec<DMatrix<T>> {
+        tiles.chunks(self.n_tiles()).map(|t| self.assemble(t)).collect()
+    }
+}
+
+/// Computes every singular triplet of every tile, then keeps in each tile
+/// the number of triplets chosen by `choose_ranks`, the triplets being
+/// stored with `write`.
+pub fn reduce_tiles<N>(tiles: Vec<DMatrix<N>>, tiling: &Tiling,
+                       options: &Options, eps: N, write: WriteVectors<N>)
+    -> Result<(Vec<SVDVectors<N>>, Convergence), Error>
+    where N: RealField
+    {
+    let mut all = Vec::with_capacity(tiles.len());
+    let mut converged = true;
+    let mut residuals = Vec::with_capacity(tiles.len());
+    let mut energy = 0.0;
+
+    for tile in tiles {
+        let k = tile.nrows().min(tile.ncols());
+        energy += nalgebra::try_convert::<N, f64>(tile.norm_squared())
+            .unwrap_or(0.0);
+        let (vectors, convergence) = truncated_svd(tile, k,
+                                                   &options.svd_method, eps,
+                                                   options.n_iter)?;
+        converged &= convergence.converged;
+        residuals.push(convergence.residuals);
+        all.push(vectors);
+    }
+
+    let none = vec![0; all.len()];
+    let ranks = choose_ranks(&all, tiling, options, energy, (&none, energy),
+                             write)?;
+    plot_tiles(&all, &ranks, energy, options)?;
+
+    let mut kept_residuals = Vec::new();
+    let mut kept = 0.0;
+    for ((vectors, r), n) in all.iter_mut().zip(residuals).zip(ranks) {
+        vectors.truncate(n);
+        kept_residuals.extend(r.into_iter().take(n));
+        kept += energy_of(vectors);
+    }
+    report_energy(kept, energy);
+
+    Ok((all, Convergence { converged, residuals: kept_residuals }))
+}
+
+/// Drops triplets of already computed tiles, see `choose_ranks`.
+pub fn remove_tile_vectors<N>(tiles: &mut Vec<SVDVectors<N>>, tiling: &Tiling,
+                              options: &Options, write: WriteVectors<N>) 
+    -> Result<(), Error>
+    where N: RealField
+    {
+    let energy = tiles.iter().map(energy_of).sum();
+    let none = vec![0; tiles.len()];
+    let ranks = choose_ranks(tiles, tiling, options, energy, (&none, energy),
+                             write)?;
+    plot_tiles(tiles, &ranks, energy, options)?;
+    for (vectors, n) in tiles.iter_mut().zip(ranks) {
+        vectors.truncate(n);
+    }
+    report_energy(tiles.iter().map(energy_of).sum(), energy);
+    Ok(())
+}
+
+/// First singular triplets of the difference between every tile and its
+/// approximation by the `old` triplets of the tile, as many as the
+/// compression policy asks for the whole matrix (see `choose_ranks`), the
+/// `old` ones being kept.
+pub fn refine_tiles<N>(tiles: Vec<DMatrix<N>>, old: &[SVDVectors<N>],
+                       tiling: &Tiling, options: &Options, eps: N, 
+                       write: WriteVectors<N>)
+    -> Result<Vec<SVDVectors<N>>, Error>
+    where N: RealField
+    {
+    let mut all = Vec::with_capacity(tiles.len());
+    let mut converged = true;
+    let mut residuals = Vec::with_capacity(tiles.len());
+    let mut energy = 0.0;
+    let mut error = 0.0;
+
+    for (tile, vectors) in tiles.into_iter().zip(old) {
+        energy += nalgebra::try_convert::<N, f64>(tile.norm_squared())
+            .unwrap_or(0.0);
+        let mut residual = tile;
+        for (sv, u, v) in vectors.iter() {
+            residual.ger(-*sv, u, v, N::one());
+        }
+        error += nalgebra::try_convert::<N, f64>(residual.norm_squared())
+            .unwrap_or(0.0);
+
+        let k = residual.nrows().min(residual.ncols()) - vectors.len();
+        let mut triplets = vectors.clone();
+        if k > 0 {
+            let (new, convergence) = truncated_svd(residual, k,
+                                                   &options.svd_method, eps,
+                                                   options.n_iter)?;
+            converged &= convergence.converged;
+            residuals.push(convergence.residuals);
+            triplets.extend(new);
+        } else {
+            residuals.push(vec![]);
+        }
+        all.push(triplets);
+    }
+
+    let stored: Vec<usize> = old.iter().map(|v| v.len()).collect();
+    let ranks = choose_ranks(&all, tiling, options, energy, (&stored, error),
+                             write)?;
+    if ranks == stored {
+        return Err(Error::NothingToRefine);
+    }
+
+    let mut new = Vec::with_capacity(all.len());
+    let mut kept_residuals = Vec::new();
+    for (((mut vectors, r), n), s) in all.into_iter().zip(residuals)
+                                         .zip(ranks).zip(stored.iter()) {
+        vectors.truncate(n);
+        kept_residuals.extend(r.into_iter().take(n - s));
+        new.push(vectors.split_off(*s));
+    }
+    report_convergence(&Convergence { converged, residuals: kept_residuals },
+                       options);
+    println!("Adding {} vectors to the {} stored ones.", 
+             new.iter().map(|v| v.len()).sum::<usize>(), 
+             stored.iter().sum::<usize>());
+    Ok(new)
+}
+
+/// Draws the singular values of all tiles together, see `plot_if_asked`.
+/// The cut is after as many values as the `ranks` keep, even though they
+/// are not always the largest ones.
+fn plot_tiles<N: RealField>(tiles: &[SVDVectors<N>], ranks: &[usize],
+                            energy: f64, options: &Options) 
+    -> Result<(), Error> {
+    if options.spectrum_plot.is_none() {
+        return Ok(());
+    }
+    let mut values: Vec<f64> = tiles.iter().flat_map(singular_values).collect();
+    values.sort_by(|a, b| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+    plot_if_asked(&values, ranks.iter().sum(), energy, options)
+}
+
+/// Number of triplets to keep in each tile to follow the compression policy,
+/// `energy` being the squared norm of the tiled matrices. The first 
+/// `kept.0` triplets of every tile are kept whatever the policy, leaving
+/// the squared error `kept.1`.
+fn choose_ranks<N>(tiles: &[SVDVectors<N>], tiling: &Tiling, 
+                   options: &Options, energy: f64, kept: (&[usize], f64),
+                   write: WriteVectors<N>)
+    -> Result<Vec<usize>, Error>
+    where N: RealField
+    {
+    let header = FileHeader::from_options(options, None);
+    let (kept, error) = kept;
+    if let CompressionPolicy::Size(max) = options.policy {
+        if header.entropy_coded {
+            let ranks = |budget: usize| {
+                allocate_ranks(tiles, tiling, options, budget as f64, 
+                               (kept, error), 0.0)
+            };
+            return ranks_for_size(tiles, tiling, &header, max as usize, 
+                                  write, ranks);
+        }
+    }
+
+    let allowed = options.allowed_error(energy, n_values(tiling))?;
+    let budget = budget(tiling, options, &header)?;
+    Ok(allocate_ranks(tiles, tiling, options, budget, (kept, error), allowed))
+}
+
+/// Ranks allocated by `ranks` with the largest budget such that the
+/// compressed file is at most `max` bytes.
+fn ranks_for_size<N, F>(tiles: &[SVDVectors<N>], tiling: &Tiling, 
+                        header: &FileHeader, max: usize, 
+                        write: WriteVectors<N>, ranks: F) 
+    -> Result<Vec<usize>, Error>
+    where N: RealField,
+          F: Fn(usize) -> Vec<usize>
+    {
+    let mut bytes = Vec::with_capacity(tiles.len());
+    for vectors in tiles {
+        bytes.push(serialize(vectors, header, write)?);
+    }
+    let sizes: Vec<usize> = tiling.blocks().iter().map(|&(h, w)| {
+        header.triplet_size(h, w)
+    }).collect();
+    let overhead = header.overhead(Some(tiling.n_blocks()));
+
+    let size = |ranks: &[usize]| {
+        let mut body = Vec::new();
+        for ((b, &n), &s) in bytes.iter().zip(ranks).zip(sizes.iter()) {
+            body.extend_from_slice(&b[..n * s]);
+        }
+        overhead + payload_size(&body, header)
+    };
+
+    if size(&ranks(0)) > max {
+        return Err(Error::RatioTooRestrictive);
+    }
+    let total = bytes.iter().map(|b| b.len()).sum();
+    let budget = largest_fitting(0, total, |b| Ok(size(&ranks(b)) <= max))?;
+    Ok(ranks(budget))
+}
+
+fn n_values(tiling: &Tiling) -> usize {
+    tiling.height * tiling.width * tiling.planes
+}
+
+/// Bytes available for the triplets of all tiles: the size of the triplets
+/// the compression policy would keep for the whole matrix (for each plane if
+/// a number of triplets is asked), minus the table of ranks. Unlimited if
+/// the policy targets a quality or an energy, exact if it asks a size (for
+/// an uncompressed body).
+fn budget(tiling: &Tiling, options: &Options, header: &FileHeader) 
+    -> Result<f64, Error> {
+    if options.policy.targets_error() {
+        return Ok(std::f64::INFINITY);
+    }
+    if let CompressionPolicy::Size(max) = options.policy {
+        let overhead = header.overhead(Some(tiling.n_blocks()));
+        if (max as usize) < overhead {
+            return Err(Error::RatioTooRestrictive);
+        }
+        return Ok((max as usize - overhead) as f64);
+    }
+    let mut whole = options.n_with(tiling.height, tiling.width)? as f64 *
+                    options.triplet_cost(tiling.height, tiling.width);
+    if let CompressionPolicy::Number(_) = options.policy {
+        whole *= tiling.planes as f64;
+    }
+    Ok(whole - 4.0 * tiling.n_blocks() as f64)
+}
+
+/// Chooses the number of triplets to keep in each tile so that their total
+/// cost does not exceed `budget` bytes. Triplets are taken greedily by
+/// decreasing `sv^2 / cost`, that is the squared error they remove per byte,
+/// weighted by the importance of the plane of the tile (see
+/// `ColourSpace::weight`). The first `kept.0` triplets of every tile are
+/// taken first, whatever they cost, leaving the squared error `kept.1`. 
+/// Stops as soon as the squared error left is at most `allowed`.
+pub fn allocate_ranks<N>(tiles: &[SVDVectors<N>], tiling: &Tiling,
+                         options: &Options, budget: f64, 
+                         kept: (&[usize], f64), allowed: f64) -> Vec<usize>
+    where N: RealField
+    {
+    let costs: Vec<f64> = tiling.blocks().iter().map(|&(h, w)| {
+        options.triplet_cost(h, w)
+    }).collect();
+
+    let mut candidates = Vec::new();
+    for (t, vectors) in tiles.iter().enumerate() {
+        let weight = options.colour_space.weight(t / tiling.n_tiles());
+        for (i, triplet) in vectors.iter().enumerate() {
+            let sv = nalgebra::try_convert::<N, f64>(triplet.0).unwrap_or(0.0);
+            candidates.push((weight * sv * sv / costs[t], t, i, sv * sv));
+        }
+    }
+    candidates.sort_by(|a, b| {
+        b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal)
+           .then(a.2.cmp(&b.2))
+    });
+
+    let mut ranks = kept.0.to_vec();
+    let mut spent: f64 = ranks.iter().zip(costs.iter())
+                              .map(|(&n, c)| n as f64 * c).sum();
+    let mut error = kept.1;
+    for &(_, t, i, sv2) in candidates.iter() {
+        if error <= allowed {
+            break;
+        }
+        // triplets of a tile are taken in order
+        if ranks[t] == i && spent + costs[t] <= budget {
+            ranks[t] += 1;
+            spent += costs[t];
+            error -= sv2;
+        }
+    }
+
+    ranks
+}
+
+/// Writes the dimensions of the matrix, the size of the tiles and the number
+/// of triplets of each tile of each plane.
+pub fn write_tiles_header<N: Scalar>(fw: &mut FileWriter, tiling: &Tiling,
+                                     tiles: &[SVDVectors<N>])
+    -> Result<(), Error> {
+
+    fw.write_u32(tiling.height as u32)?;
+    fw.write_u32(tiling.width as u32)?;
+    fw.write_u32(tiling.tile_height as u32)?;
+    fw.write_u32(tiling.tile_width as u32)?;
+    for vectors in tiles {
+        fw.write_u32(vectors.len() as u32)?;
+    }
+    Ok(())
+}
+
+/// Reads what `write_tiles_header` wrote.
+pub fn read_tiles_header(fr: &mut FileReader, header: &FileHeader)
+    -> Result<(Tiling, Vec<usize>), Error> {
+
+    let height = fr.read_u32()? as usize;
+    let width = fr.read_u32()? as usize;
+    let tile_height = fr.read_u32()? as usize;
+    let tile_width = fr.read_u32()? as usize;
+    header.validate_tiling(height, width, tile_height, tile_width)?;
+
+    let tiling = Tiling { height, width, tile_height, tile_width, 
+                          planes: header.n_planes() };
+    if fr.remaining()? < 4 * tiling.n_blocks() as u64 {
+        return Err(Error::InvalidHeader("file is truncated"));
+    }
+    let mut ranks = Vec::with_capacity(tiling.n_blocks());
+    for _i in 0..tiling.n_blocks() {
+        ranks.push(fr.read_u32()? as usize);
+    }
+
+    for (&(h, w), &n) in tiling.blocks().iter().zip(ranks.iter()) {
+        if n > h.min(w) {
+            return Err(Error::InvalidHeader("invalid number of triplets"));
+        }
+    }
+    let size = tiles_size(&tiling, &ranks, header);
+    if !header.entropy_coded && fr.remaining()? < size as u64 {
+        return Err(Error::InvalidHeader("file is truncated"));
+    }
+
+    Ok((tiling, ranks))
+}
+
+/// Writes the tile table and the triplets of every tile with `write`.
+pub fn write_tiles<N: Scalar>(fw: &mut FileWriter, header: &FileHeader,
+    tiling: &Tiling, tiles: &[SVDVectors<N>], write: WriteVectors<N>)
+    -> Result<(), Error> {
+
+    write_tiles_header(fw, tiling, tiles)?;
+    write_payload(fw, header, |w| {
+        for vectors in tiles {
+            write(w, vectors, header)?;
+        }
+        Ok(())
+    })
+}
+
+/// Reads the tile table and the triplets of every tile with `read`.
+pub fn read_tiles<N: Scalar>(fr: &mut FileReader, header: &FileHeader,
+    read: ReadVectors<N>)
+    -> Result<(Tiling, Vec<SVDVectors<N>>), Error> {
+
+    let (tiling, ranks) = read_tiles_header(fr, header)?;
+    let size = tiles_size(&tiling, &ranks, header);
+    let tiles = read_payload(fr, header, size, |r| {
+        let mut tiles = Vec::with_capacity(ranks.len());
+        for (&(h, w), &n) in tiling.blocks().iter().zip(ranks.iter()) {
+            tiles.push(read(r, header, n, h, w)?);
+        }
+        Ok(tiles)
+    })?;
+
+    Ok((tiling, tiles))
+}
+
+/// Recomputes the whole matrix of every plane from the triplets of every
+/// tile.
+pub fn recompute_tiles<N: Scalar, T: Sample>(tiling: &Tiling, 
+    tiles: &[SVDVectors<N>],
+    recompute: fn(&SVDVectors<N>) -> Result<DMatrix<T>, Error>)
+    -> Result<Vec<DMatrix<T>>, Error> {
+
+    let mut matrices = Vec::with_capacity(tiles.len());
+    for (&(h, w), vectors) in tiling.blocks().iter().zip(tiles) {
+        if vectors.is_empty() {
+            matrices.push(DMatrix::from_element(h, w, T::ZERO));
+        } else {
+            matrices.push(recompute(vectors)?);
+        }
+    }
+    Ok(tiling.assemble_planes(&matrices))
+}
+
+/// Size in bytes of the triplets of all tiles, before entropy coding.
+pub fn tiles_size(tiling: &Tiling, ranks: &[usize], header: &FileHeader)
+    -> usize {
+    tiling.blocks().iter().zip(ranks).map(|(&(h, w), &n)| {
+        n * header.triplet_size(h, w)
+    }).sum()
+}
+
+#[allow(dead_code, unused_imports)]
+mod tests {
+    use super::{Tiling, allocate_ranks};
+    use crate::encode::{Options, CompressionPolicy};
+    use nalgebra::{DMatrix, DVector};
+
+    #[test]
+    fn test_split_assemble() {
+        let m = DMatrix::<i32>::from_fn(37, 20, |i, j| (i * 100 + j) as i32);
+        let tiling = Tiling::new(37, 20, 8, 8);
+        assert_eq!((tiling.n_rows(), tiling.n_cols()), (5, 3));
+        let tiles = tiling.split(&m);
+        assert_eq!(tiles[14].shape(), (5, 4));
+        assert_eq!(tiling.assemble(&tiles), m);
+    }
+
+    #[test]
+    fn test_allocate_ranks() {
+        let mut options = Options::default();
+        options.policy = CompressionPolicy::with_number(1);
+        let tiling = Tiling::new(4, 8, 4, 4);
+        let triplet = |sv: f64| (sv, DVector::zeros(4), DVector::zeros(4));
+        let tiles = vec![
+            vec![triplet(10.0), triplet(1.0), triplet(0.5)],
+            vec![triplet(5.0), triplet(4.0), triplet(3.0)],
+        ];
+        let cost = options.triplet_cost(4, 4);
+
+        let energy = 100.0 + 1.0 + 0.25 + 25.0 + 16.0 + 9.0;
+        let allocate = |budget, allowed| {
+            allocate_ranks(&tiles, &tiling, &options, budget, 
+                           (&[0, 0], energy), allowed)
+        };
+        assert_eq!(allocate(3.5 * cost, 0.0), vec![1, 2]);
+        assert_eq!(allocate(0.5 * cost, 0.0), vec![0, 0]);
+        assert_eq!(allocate(10.0 * cost, 0.0), vec![3, 3]);
+        assert_eq!(allocate(10.0 * cost, 12.0), vec![1, 2]);
+        assert_eq!(allocate(10.0 * cost, 9.0), vec![1, 3]);
+
+        // stored triplets are kept even if they exceed the budget
+        let kept = allocate_ranks(&tiles, &tiling, &options, 3.5 * cost,
+                                  (&[3, 0], energy - 101.25), 0.0);
+        assert_eq!(kept, vec![3, 0]);
+        let kept = allocate_ranks(&tiles, &tiling, &options, 5.0 * cost,
+                                  (&[3, 0], energy - 101.25), 0.0);
+        assert_eq!(kept, vec![3, 2]);
+    }
+}
@@ -7,23 +7,65 @@ use std::{
     path::Path
 };
 
+/// Where a `FileWriter` writes its bytes.
+enum Sink {
+    File(File),
+    Memory(Vec<u8>),
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IOError> {
+        match self {
+            Sink::File(f) => f.write(buf),
+            Sink::Memory(v) => v.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), IOError> {
+        match self {
+            Sink::File(f) => f.flush(),
+            Sink::Memory(v) => v.flush(),
+        }
+    }
+}
+
 pub struct FileWriter {
-    file: File
+    file: Sink,
+    bit_buffer: u32,
+    bit_count: u8,
 }
 
 #[allow(dead_code)]
 impl FileWriter {
     pub fn new(f: File) -> Self {
         Self {
-            file: f
+            file: Sink::File(f),
+            bit_buffer: 0,
+            bit_count: 0,
         }
     }    
 
+    /// Creates a writer that keeps the bytes in memory, see `into_bytes`.
+    pub fn in_memory() -> Self {
+        Self {
+            file: Sink::Memory(Vec::new()),
+            bit_buffer: 0,
+            bit_count: 0,
+        }
+    }
+
+    /// Returns the bytes written by a writer created with `in_memory`, 
+    /// nothing if it writes to a file.
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self.file {
+            Sink::Memory(v) => v,
+            Sink::File(_) => Vec::new(),
+        }
+    }
+
     pub fn from_path(path: &Path) -> Result<Self, IOError> {
         let file = File::create(path)?;
-        Ok(Self {
-            file: file
-        })
+        Ok(Self::new(file))
     }
 
     pub fn from_name(name: &str) -> Result<Self, IOError> {
@@ -36,9 +78,7 @@ impl FileWriter {
     }
 
     pub fn write_all(&mut self, x: &[u8]) -> Result<(), IOError> {
-        for i in 0..x.len() {
-            self.write_u8(x[i])?;
-        }
+        self.file.write_all(x)?;
         Ok(())
     }
 
@@ -76,4 +116,29 @@ impl FileWriter {
         self.file.write_all(&x.to_be_bytes())?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Writes the `n` least significant bits of `x` (`n <= 16`), most 
+    /// significant first. Bytes are only written once full, see `flush_bits`.
+    pub fn write_bits(&mut self, x: u32, n: u8) -> Result<(), IOError> {
+        let mask = (1_u32 << n) - 1;
+        self.bit_buffer = (self.bit_buffer << n) | (x & mask);
+        self.bit_count += n;
+        while self.bit_count >= 8 {
+            self.bit_count -= 8;
+            self.write_u8((self.bit_buffer >> self.bit_count) as u8)?;
+        }
+        self.bit_buffer &= (1_u32 << self.bit_count) - 1;
+        Ok(())
+    }
+
+    /// Writes the pending bits, padding the last byte with zeros.
+    pub fn flush_bits(&mut self) -> Result<(), IOError> {
+        if self.bit_count > 0 {
+            let x = (self.bit_buffer << (8 - self.bit_count)) as u8;
+            self.bit_buffer = 0;
+            self.bit_count = 0;
+            self.write_u8(x)?;
+        }
+        Ok(())
+    }
+}